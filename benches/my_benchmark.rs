@@ -1,8 +1,22 @@
-use criterion::{criterion_group, criterion_main, Criterion};
-// use codecrafters_dns_server::fibonacci;
+use codecrafters_dns_server::dns_protocol::dns_message::DnsMessage;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
 
 pub fn criterion_benchmark(c: &mut Criterion) {
-    //     c.bench_function("fib 20", |b| b.iter(|| fibonacci(black_box(20))));
+    // www.example.com A query with one A answer
+    let response = [
+        0x12, 0x34, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x03, 0x77, 0x77,
+        0x77, 0x07, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00, 0x00,
+        0x01, 0x00, 0x01, 0x03, 0x77, 0x77, 0x77, 0x07, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65,
+        0x03, 0x63, 0x6f, 0x6d, 0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x0e, 0x10, 0x00, 0x04,
+        0x5d, 0xb8, 0xd8, 0x22,
+    ];
+    c.bench_function("parse response", |b| {
+        b.iter(|| DnsMessage::from_bytes(black_box(&response)))
+    });
+    let message = DnsMessage::from_bytes(&response).unwrap();
+    c.bench_function("serialize response", |b| {
+        b.iter(|| black_box(&message).to_bytes())
+    });
 }
 
 criterion_group!(benches, criterion_benchmark);
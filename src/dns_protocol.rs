@@ -1,3 +1,9 @@
+pub mod dns_edns;
+pub mod dns_encoder;
+pub mod dns_error;
+pub mod dns_field_codes;
 pub mod dns_header;
+pub mod dns_message;
+pub mod dns_name;
 pub mod dns_question;
 pub mod dns_resource_record;
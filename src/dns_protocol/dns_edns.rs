@@ -0,0 +1,105 @@
+use crate::dns_protocol::{
+    dns_field_codes::{RecordClass, RecordType},
+    dns_name::DomainName,
+    dns_resource_record::{RData, ResourceRecord},
+};
+
+/// What a requestor that doesn't send an OPT record is assumed to handle (RFC 1035)
+pub const DEFAULT_UDP_PAYLOAD_SIZE: u16 = 512;
+
+const DNSSEC_OK_FLAG: u16 = 0x8000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
+/// The contents of an OPT pseudo-record (RFC 6891). The extended RCODE bits it carries are
+/// folded into `DnsHeader::response_code` by `DnsMessage`, so they don't appear here.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edns {
+    pub udp_payload_size: u16,
+    pub version: u8,
+    pub dnssec_ok: bool,
+    pub options: Vec<EdnsOption>,
+}
+
+impl Default for Edns {
+    fn default() -> Self {
+        Self {
+            udp_payload_size: DEFAULT_UDP_PAYLOAD_SIZE,
+            version: 0,
+            dnssec_ok: false,
+            options: Vec::new(),
+        }
+    }
+}
+
+impl Edns {
+    /// Splits an OPT record into its EDNS fields and the upper 8 bits of the extended RCODE.
+    pub fn from_record(record: &ResourceRecord) -> (Self, u8) {
+        let [extended_rcode, version, flags_high, flags_low] = record.ttl.to_be_bytes();
+        let options = match &record.data {
+            RData::Opt(options) => options.clone(),
+            _ => Vec::new(),
+        };
+        let edns = Self {
+            udp_payload_size: u16::from(record.class).max(DEFAULT_UDP_PAYLOAD_SIZE),
+            version,
+            dnssec_ok: u16::from_be_bytes([flags_high, flags_low]) & DNSSEC_OK_FLAG != 0,
+            options,
+        };
+        (edns, extended_rcode)
+    }
+
+    pub fn to_record(&self, extended_rcode: u8) -> ResourceRecord {
+        let flags = if self.dnssec_ok { DNSSEC_OK_FLAG } else { 0 };
+        let [flags_high, flags_low] = flags.to_be_bytes();
+        ResourceRecord::new(
+            DomainName::root(),
+            RecordType::Opt,
+            RecordClass::from(self.udp_payload_size),
+            u32::from_be_bytes([extended_rcode, self.version, flags_high, flags_low]),
+            RData::Opt(self.options.clone()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_from_record() {
+        let edns = Edns {
+            udp_payload_size: 1232,
+            version: 0,
+            dnssec_ok: true,
+            options: vec![EdnsOption {
+                code: 10,
+                data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+            }],
+        };
+        let record = edns.to_record(1);
+        assert_eq!(record.ttl, 0x01008000);
+        assert_eq!(u16::from(record.class), 1232);
+        let bytes = record.to_bytes();
+        assert_eq!(
+            bytes,
+            [0, 0, 41, 4, 208, 1, 0, 128, 0, 0, 12, 0, 10, 0, 8, 1, 2, 3, 4, 5, 6, 7, 8]
+        );
+        let (decoded, _) = ResourceRecord::from_bytes(&bytes, 0).unwrap();
+        assert_eq!(Edns::from_record(&decoded), (edns, 1));
+    }
+
+    #[test]
+    fn test_small_payload_size_treated_as_512() {
+        let record = Edns {
+            udp_payload_size: 100,
+            ..Default::default()
+        }
+        .to_record(0);
+        assert_eq!(Edns::from_record(&record).0.udp_payload_size, 512);
+    }
+}
@@ -0,0 +1,122 @@
+use crate::dns_protocol::dns_name::{DomainName, Label};
+use std::collections::HashMap;
+
+// Compression pointers only have 14 bits for the offset
+const MAX_POINTER_OFFSET: usize = 0x3FFF;
+
+/// Builds a wire-format message, remembering where each name suffix was written so later
+/// occurrences can be replaced with an RFC 1035 compression pointer.
+#[derive(Debug, Default)]
+pub struct DnsEncoder {
+    bytes: Vec<u8>,
+    compress: bool,
+    names: HashMap<DomainName, u16>,
+}
+
+impl DnsEncoder {
+    pub fn new() -> Self {
+        Self {
+            compress: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn uncompressed() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.write_bytes(&value.to_be_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.write_bytes(&value.to_be_bytes());
+    }
+
+    /// Overwrites two bytes already written at `offset`, e.g. to backfill an RDLENGTH.
+    pub fn set_u16(&mut self, offset: usize, value: u16) {
+        self.bytes[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+    }
+
+    pub fn write_name(&mut self, name: &DomainName) {
+        if !self.compress {
+            return self.write_uncompressed_name(name);
+        }
+        for (suffix, label) in name.suffixes().zip(name.iter_labels()) {
+            if let Some(offset) = self.names.get(&suffix) {
+                self.write_u16(0xC000 | offset);
+                return;
+            }
+            if self.bytes.len() <= MAX_POINTER_OFFSET {
+                self.names.insert(suffix, self.bytes.len() as u16);
+            }
+            self.write_label(label);
+        }
+        self.bytes.push(0);
+    }
+
+    /// Writes a name without using or recording pointers, for RDATA fields that must not be
+    /// compressed (e.g. the SRV target, RFC 2782).
+    pub fn write_uncompressed_name(&mut self, name: &DomainName) {
+        for label in name.iter_labels() {
+            self.write_label(label);
+        }
+        self.bytes.push(0);
+    }
+
+    fn write_label(&mut self, label: &Label) {
+        self.bytes.push(label.len() as u8);
+        self.bytes.extend_from_slice(label.as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(text: &str) -> DomainName {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn test_write_name_compressed() {
+        let mut encoder = DnsEncoder::new();
+        encoder.write_bytes(&[0; 12]);
+        encoder.write_name(&name("www.example.com"));
+        encoder.write_name(&name("mail.EXAMPLE.com"));
+        encoder.write_name(&name("www.example.com"));
+        encoder.write_name(&name("org"));
+        let bytes = encoder.into_bytes();
+        assert_eq!(
+            bytes[12..],
+            [
+                3, b'w', b'w', b'w', 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o',
+                b'm', 0, 4, b'm', b'a', b'i', b'l', 0xC0, 16, 0xC0, 12, 3, b'o', b'r', b'g', 0
+            ]
+        );
+    }
+
+    #[test]
+    fn test_write_name_uncompressed() {
+        let mut encoder = DnsEncoder::uncompressed();
+        encoder.write_name(&name("example.com"));
+        encoder.write_name(&name("example.com"));
+        assert_eq!(encoder.len(), 26);
+    }
+}
@@ -0,0 +1,44 @@
+use crate::dns_protocol::dns_field_codes::RecordType;
+use thiserror::Error;
+
+/// Why a message (or part of one) couldn't be decoded. Offsets are absolute byte positions in
+/// the message being parsed.
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    #[error("message truncated: needed data at byte {offset}")]
+    Truncated { offset: usize },
+    #[error("header claims {count} {section} records but the message ends at byte {offset}")]
+    BadCount {
+        offset: usize,
+        section: &'static str,
+        count: u16,
+    },
+    #[error("reserved label type {label_type:#04x} at byte {offset}")]
+    BadLabelType { offset: usize, label_type: u8 },
+    #[error("name at byte {offset} is longer than 255 bytes")]
+    NameTooLong { offset: usize },
+    #[error("compression pointer at byte {offset} does not point backwards (target {target})")]
+    BadPointer { offset: usize, target: usize },
+    #[error("too many compression pointers in name at byte {offset}")]
+    PointerLoop { offset: usize },
+    #[error("second OPT record at byte {offset}")]
+    DuplicateOpt { offset: usize },
+    #[error("malformed RDATA for record type {record_type} at byte {offset}")]
+    BadRData {
+        offset: usize,
+        record_type: RecordType,
+    },
+}
+
+/// Why a name in presentation format couldn't be parsed
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum ParseNameError {
+    #[error("empty label")]
+    EmptyLabel,
+    #[error("label is {length} bytes long (max 63)")]
+    LabelTooLong { length: usize },
+    #[error("name is longer than 255 bytes")]
+    NameTooLong,
+    #[error("bad escape sequence")]
+    BadEscape,
+}
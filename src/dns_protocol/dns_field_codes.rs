@@ -0,0 +1,168 @@
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+#[error("unrecognised {kind} {text:?}")]
+pub struct ParseCodeError {
+    kind: &'static str,
+    text: String,
+}
+
+/// Declares an enum over a numeric DNS field with one variant per known value plus an
+/// `Unknown` variant, so every value on the wire survives a decode/encode round trip. Display
+/// and FromStr use the mnemonic, falling back to the RFC 3597 style `<prefix><number>` form.
+macro_rules! dns_code {
+    (
+        $(#[$meta:meta])*
+        $name:ident($repr:ty), $kind:literal, $prefix:literal {
+            $($(#[$variant_meta:meta])* $variant:ident = $value:literal => $text:literal,)*
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub enum $name {
+            $($(#[$variant_meta])* $variant,)*
+            Unknown($repr),
+        }
+
+        impl From<$repr> for $name {
+            fn from(value: $repr) -> Self {
+                match value {
+                    $($value => $name::$variant,)*
+                    other => $name::Unknown(other),
+                }
+            }
+        }
+
+        impl From<$name> for $repr {
+            fn from(value: $name) -> Self {
+                match value {
+                    $($name::$variant => $value,)*
+                    $name::Unknown(other) => other,
+                }
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                match self {
+                    $($name::$variant => f.write_str($text),)*
+                    $name::Unknown(other) => write!(f, concat!($prefix, "{}"), other),
+                }
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = ParseCodeError;
+
+            fn from_str(text: &str) -> Result<Self, Self::Err> {
+                let upper = text.to_ascii_uppercase();
+                match upper.as_str() {
+                    $($text => Ok($name::$variant),)*
+                    _ => upper
+                        .strip_prefix($prefix)
+                        .and_then(|number| number.parse::<$repr>().ok())
+                        .map($name::from)
+                        .ok_or_else(|| ParseCodeError {
+                            kind: $kind,
+                            text: text.to_string(),
+                        }),
+                }
+            }
+        }
+    };
+}
+
+dns_code! {
+    #[derive(Default)]
+    Opcode(u8), "opcode", "OPCODE" {
+        #[default]
+        Query = 0 => "QUERY",
+        InverseQuery = 1 => "IQUERY",
+        Status = 2 => "STATUS",
+        Notify = 4 => "NOTIFY",
+        Update = 5 => "UPDATE",
+    }
+}
+
+dns_code! {
+    /// Wide enough for the 12-bit extended RCODE that EDNS allows
+    #[derive(Default)]
+    ResponseCode(u16), "response code", "RCODE" {
+        #[default]
+        NoError = 0 => "NOERROR",
+        FormatError = 1 => "FORMERR",
+        ServerFailure = 2 => "SERVFAIL",
+        NameError = 3 => "NXDOMAIN",
+        NotImplemented = 4 => "NOTIMP",
+        Refused = 5 => "REFUSED",
+        YxDomain = 6 => "YXDOMAIN",
+        YxRrSet = 7 => "YXRRSET",
+        NxRrSet = 8 => "NXRRSET",
+        NotAuth = 9 => "NOTAUTH",
+        NotZone = 10 => "NOTZONE",
+        BadVersion = 16 => "BADVERS",
+    }
+}
+
+dns_code! {
+    RecordType(u16), "record type", "TYPE" {
+        A = 1 => "A",
+        Ns = 2 => "NS",
+        Cname = 5 => "CNAME",
+        Soa = 6 => "SOA",
+        Ptr = 12 => "PTR",
+        Mx = 15 => "MX",
+        Txt = 16 => "TXT",
+        Aaaa = 28 => "AAAA",
+        Srv = 33 => "SRV",
+        Opt = 41 => "OPT",
+        Any = 255 => "ANY",
+        Caa = 257 => "CAA",
+    }
+}
+
+dns_code! {
+    RecordClass(u16), "record class", "CLASS" {
+        In = 1 => "IN",
+        Cs = 2 => "CS",
+        Ch = 3 => "CH",
+        Hs = 4 => "HS",
+        Any = 255 => "ANY",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_numbers() {
+        for value in 0..=u16::MAX {
+            assert_eq!(u16::from(RecordType::from(value)), value);
+            assert_eq!(u16::from(RecordClass::from(value)), value);
+        }
+        for value in 0..=15u8 {
+            assert_eq!(u8::from(Opcode::from(value)), value);
+        }
+        assert_eq!(RecordType::from(28), RecordType::Aaaa);
+        assert_eq!(RecordType::from(65280), RecordType::Unknown(65280));
+    }
+
+    #[test]
+    fn test_display_from_str() {
+        assert_eq!(RecordType::Aaaa.to_string(), "AAAA");
+        assert_eq!(RecordType::Unknown(65280).to_string(), "TYPE65280");
+        assert_eq!(RecordClass::Unknown(254).to_string(), "CLASS254");
+        assert_eq!(ResponseCode::NameError.to_string(), "NXDOMAIN");
+        assert_eq!("mx".parse::<RecordType>(), Ok(RecordType::Mx));
+        assert_eq!("TYPE1".parse::<RecordType>(), Ok(RecordType::A));
+        assert_eq!("TYPE65280".parse(), Ok(RecordType::Unknown(65280)));
+        assert_eq!("ch".parse::<RecordClass>(), Ok(RecordClass::Ch));
+        assert_eq!("SERVFAIL".parse(), Ok(ResponseCode::ServerFailure));
+        assert_eq!("Notify".parse(), Ok(Opcode::Notify));
+        assert!("BOGUS".parse::<RecordType>().is_err());
+        assert!("TYPE70000".parse::<RecordType>().is_err());
+    }
+}
@@ -1,5 +1,5 @@
+use crate::dns_protocol::dns_field_codes::{Opcode, ResponseCode};
 use bitfield::bitfield;
-// use rkyv::{deserialize, rancor::Error, Archive, Deserialize, Serialize};
 
 bitfield! {
     pub struct DnsHeaderFlags(u16);
@@ -14,19 +14,24 @@ bitfield! {
 }
 
 pub const DNS_HEADER_SIZE: usize = 12;
+
+/// Bits of `DnsHeader::reserved` given meaning by DNSSEC: AD says the data has been validated
+/// (RFC 4035 section 3.2.3) and CD asks for it not to be (section 3.2.2). The top bit is still Z.
+pub const AUTHENTIC_DATA: u8 = 0b010;
+pub const CHECKING_DISABLED: u8 = 0b001;
 // #[repr(packed(1))]
-#[derive(Debug, PartialEq, Default)]
+#[derive(Debug, PartialEq, Default, Clone, Copy)]
 pub struct DnsHeader {
     pub packet_identifier: u16,
     // pub flags: DnsHeaderFlags,
-    pub query_response_indicator: u8,
-    pub opcode: u8,
-    pub authoritative_answer: u8,
-    pub truncation: u8,
-    pub recursion_desired: u8,
-    pub recursion_available: u8,
+    pub query_response_indicator: bool,
+    pub opcode: Opcode,
+    pub authoritative_answer: bool,
+    pub truncation: bool,
+    pub recursion_desired: bool,
+    pub recursion_available: bool,
     pub reserved: u8,
-    pub response_code: u8,
+    pub response_code: ResponseCode,
     pub question_count: u16,
     pub answer_record_count: u16,
     pub authority_record_count: u16,
@@ -52,18 +57,44 @@ pub struct DnsHeader {
 // }
 
 impl DnsHeader {
+    pub fn authentic_data(&self) -> bool {
+        self.reserved & AUTHENTIC_DATA != 0
+    }
+
+    pub fn set_authentic_data(&mut self, authentic_data: bool) {
+        self.set_reserved_bit(AUTHENTIC_DATA, authentic_data);
+    }
+
+    pub fn checking_disabled(&self) -> bool {
+        self.reserved & CHECKING_DISABLED != 0
+    }
+
+    pub fn set_checking_disabled(&mut self, checking_disabled: bool) {
+        self.set_reserved_bit(CHECKING_DISABLED, checking_disabled);
+    }
+
+    fn set_reserved_bit(&mut self, bit: u8, value: bool) {
+        if value {
+            self.reserved |= bit;
+        } else {
+            self.reserved &= !bit;
+        }
+    }
+
     pub fn to_network_bytes(&self) -> [u8; 12] {
         let mut bytes = [0; 12];
         bytes[0..=1].copy_from_slice(&self.packet_identifier.to_be_bytes());
 
         // bytes[2..=3].copy_from_slice(&self.flags.0.to_be_bytes());
 
-        bytes[2] = (self.query_response_indicator << 7)
-            | (self.opcode << 3)
-            | (self.authoritative_answer << 2)
-            | (self.truncation << 1)
-            | self.recursion_desired;
-        bytes[3] = (self.recursion_available << 7) | (self.reserved << 4) | self.response_code;
+        bytes[2] = ((self.query_response_indicator as u8) << 7)
+            | ((u8::from(self.opcode) & 0b00001111) << 3)
+            | ((self.authoritative_answer as u8) << 2)
+            | ((self.truncation as u8) << 1)
+            | self.recursion_desired as u8;
+        bytes[3] = ((self.recursion_available as u8) << 7)
+            | ((self.reserved & 0b00000111) << 4)
+            | (u16::from(self.response_code) & 0b00001111) as u8;
         bytes[4..=5].copy_from_slice(&self.question_count.to_be_bytes());
         bytes[6..=7].copy_from_slice(&self.answer_record_count.to_be_bytes());
         bytes[8..=9].copy_from_slice(&self.authority_record_count.to_be_bytes());
@@ -74,14 +105,14 @@ impl DnsHeader {
     pub fn from_network_bytes(header: &[u8; 12]) -> Self {
         Self {
             packet_identifier: u16::from_be_bytes([header[0], header[1]]),
-            query_response_indicator: header[2] >> 7,
-            opcode: (header[2] >> 3) & 0b00001111,
-            authoritative_answer: (header[2] >> 2) & 0b00000001,
-            truncation: (header[2] >> 1) & 0b00000001,
-            recursion_desired: header[2] & 0b00000001,
-            recursion_available: header[3] >> 7,
+            query_response_indicator: header[2] >> 7 == 1,
+            opcode: Opcode::from((header[2] >> 3) & 0b00001111),
+            authoritative_answer: (header[2] >> 2) & 0b00000001 == 1,
+            truncation: (header[2] >> 1) & 0b00000001 == 1,
+            recursion_desired: header[2] & 0b00000001 == 1,
+            recursion_available: header[3] >> 7 == 1,
             reserved: (header[3] >> 4) & 0b00000111,
-            response_code: header[3] & 0b00001111,
+            response_code: ResponseCode::from((header[3] & 0b00001111) as u16),
             question_count: u16::from_be_bytes([header[4], header[5]]),
             answer_record_count: u16::from_be_bytes([header[6], header[7]]),
             authority_record_count: u16::from_be_bytes([header[8], header[9]]),
@@ -98,14 +129,14 @@ mod tests {
     fn test_to_bytes() {
         let header = DnsHeader {
             packet_identifier: 0x1234,
-            query_response_indicator: 1,
-            opcode: 0,
-            authoritative_answer: 0,
-            truncation: 0,
-            recursion_desired: 1,
-            recursion_available: 1,
+            query_response_indicator: true,
+            opcode: Opcode::Query,
+            authoritative_answer: false,
+            truncation: false,
+            recursion_desired: true,
+            recursion_available: true,
             reserved: 0,
-            response_code: 0,
+            response_code: ResponseCode::NoError,
             question_count: 1,
             answer_record_count: 0,
             authority_record_count: 0,
@@ -125,14 +156,14 @@ mod tests {
         ];
         let header = DnsHeader::from_network_bytes(&bytes);
         assert_eq!(header.packet_identifier, 0x1234);
-        assert_eq!(header.query_response_indicator, 1);
-        assert_eq!(header.opcode, 0);
-        assert_eq!(header.authoritative_answer, 0);
-        assert_eq!(header.truncation, 0);
-        assert_eq!(header.recursion_desired, 1);
-        assert_eq!(header.recursion_available, 1);
+        assert!(header.query_response_indicator);
+        assert_eq!(header.opcode, Opcode::Query);
+        assert!(!header.authoritative_answer);
+        assert!(!header.truncation);
+        assert!(header.recursion_desired);
+        assert!(header.recursion_available);
         assert_eq!(header.reserved, 0);
-        assert_eq!(header.response_code, 0);
+        assert_eq!(header.response_code, ResponseCode::NoError);
         assert_eq!(header.question_count, 1);
         assert_eq!(header.answer_record_count, 0);
         assert_eq!(header.authority_record_count, 0);
@@ -143,14 +174,14 @@ mod tests {
     fn test_to_from_bytes() {
         let header = DnsHeader {
             packet_identifier: 0x5678,
-            query_response_indicator: 0,
-            opcode: 1,
-            authoritative_answer: 1,
-            truncation: 1,
-            recursion_desired: 0,
-            recursion_available: 0,
+            query_response_indicator: false,
+            opcode: Opcode::InverseQuery,
+            authoritative_answer: true,
+            truncation: true,
+            recursion_desired: false,
+            recursion_available: false,
             reserved: 1,
-            response_code: 2,
+            response_code: ResponseCode::ServerFailure,
             question_count: 2,
             answer_record_count: 3,
             authority_record_count: 4,
@@ -160,4 +191,17 @@ mod tests {
         let new_header = DnsHeader::from_network_bytes(&bytes);
         assert_eq!(header, new_header);
     }
+
+    #[test]
+    fn test_dnssec_flags() {
+        // dig's default query flags: RD and AD
+        let mut header = DnsHeader::from_network_bytes(&[0, 0, 0x01, 0x20, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert!(header.authentic_data());
+        assert!(!header.checking_disabled());
+
+        header.set_authentic_data(false);
+        header.set_checking_disabled(true);
+        assert_eq!(header.reserved, CHECKING_DISABLED);
+        assert_eq!(header.to_network_bytes()[3], 0x10);
+    }
 }
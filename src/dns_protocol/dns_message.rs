@@ -0,0 +1,477 @@
+use crate::dns_protocol::{
+    dns_edns::Edns, dns_encoder::DnsEncoder, dns_error::DecodeError, dns_field_codes::RecordType,
+    dns_field_codes::ResponseCode, dns_header::DnsHeader, dns_header::DNS_HEADER_SIZE,
+    dns_name::DomainName, dns_question::decode_questions, dns_question::DnsQuestion,
+    dns_resource_record::ResourceRecord,
+};
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct DnsMessage {
+    pub header: DnsHeader,
+    pub questions: Vec<DnsQuestion>,
+    pub answers: Vec<ResourceRecord>,
+    pub authorities: Vec<ResourceRecord>,
+    /// Additional records other than the OPT pseudo-record, which is parsed into `edns`
+    pub additionals: Vec<ResourceRecord>,
+    pub edns: Option<Edns>,
+}
+
+impl DnsMessage {
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, DecodeError> {
+        let mut header = DnsHeader::from_network_bytes(
+            buf.get(..DNS_HEADER_SIZE)
+                .ok_or(DecodeError::Truncated { offset: buf.len() })?
+                .try_into()
+                .expect("slice is DNS_HEADER_SIZE long"),
+        );
+        let (questions, questions_len) =
+            decode_questions(buf, DNS_HEADER_SIZE, header.question_count)?;
+        let mut offset = DNS_HEADER_SIZE + questions_len;
+        let answers = decode_records(buf, &mut offset, header.answer_record_count, "answer")?;
+        let authorities =
+            decode_records(buf, &mut offset, header.authority_record_count, "authority")?;
+        let mut additionals = Vec::<ResourceRecord>::new();
+        let mut edns = None;
+        for _ in 0..header.additional_record_count {
+            let record_offset = offset;
+            let record = decode_record(
+                buf,
+                &mut offset,
+                header.additional_record_count,
+                "additional",
+            )?;
+            if record.answer_type != RecordType::Opt {
+                additionals.push(record);
+                continue;
+            }
+            if edns.is_some() {
+                return Err(DecodeError::DuplicateOpt {
+                    offset: record_offset,
+                });
+            }
+            let (opt, extended_rcode) = Edns::from_record(&record);
+            header.response_code = ResponseCode::from(
+                u16::from(extended_rcode) << 4 | u16::from(header.response_code),
+            );
+            edns = Some(opt);
+        }
+        Ok(Self {
+            header,
+            questions,
+            answers,
+            authorities,
+            additionals,
+            edns,
+        })
+    }
+
+    /// Serializes the message without name compression, taking the section counts from the
+    /// section lengths rather than from whatever the header currently says.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut encoder = DnsEncoder::uncompressed();
+        self.encode(&mut encoder);
+        encoder.into_bytes()
+    }
+
+    /// Same as `to_bytes` but with repeated names replaced by compression pointers.
+    pub fn to_compressed_bytes(&self) -> Vec<u8> {
+        let mut encoder = DnsEncoder::new();
+        self.encode(&mut encoder);
+        encoder.into_bytes()
+    }
+
+    /// Compressed bytes of at most `max_size` (where possible), dropping whole records from the
+    /// end of the message until it fits. TC is only set if answer or authority records had to go;
+    /// losing additional records doesn't warrant it (RFC 2181 section 9). The OPT record is kept.
+    pub fn to_truncated_bytes(&self, max_size: usize) -> Vec<u8> {
+        let mut encoder = DnsEncoder::new();
+        let mut boundaries = Vec::new();
+        self.encode_with_boundaries(&mut encoder, |offset| boundaries.push(offset));
+        let mut bytes = encoder.into_bytes();
+        if bytes.len() <= max_size {
+            return bytes;
+        }
+
+        // Compression pointers only ever point backwards, so the message up to any record
+        // boundary stands on its own. The OPT record is a bare root name and can be moved up.
+        let opt = bytes.split_off(*boundaries.last().expect("there's always a first boundary"));
+        let budget = max_size.saturating_sub(opt.len());
+        let kept = boundaries
+            .partition_point(|&offset| offset <= budget)
+            .saturating_sub(1);
+        bytes.truncate(boundaries[kept]);
+        bytes.extend_from_slice(&opt);
+
+        let answers = kept.min(self.answers.len());
+        let authorities = (kept - answers).min(self.authorities.len());
+        let additionals = kept - answers - authorities;
+        let header = DnsHeader {
+            truncation: self.header.truncation
+                || answers + authorities < self.answers.len() + self.authorities.len(),
+            question_count: self.questions.len() as u16,
+            answer_record_count: answers as u16,
+            authority_record_count: authorities as u16,
+            additional_record_count: (additionals + self.edns.is_some() as usize) as u16,
+            ..self.header
+        };
+        bytes[..DNS_HEADER_SIZE].copy_from_slice(&header.to_network_bytes());
+        bytes
+    }
+
+    /// Gives every record owned by `name` that exact casing, e.g. to hand a client back the
+    /// casing it asked with rather than whatever upstream or the cache had
+    pub fn restore_case(&mut self, name: &DomainName) {
+        let records = self
+            .answers
+            .iter_mut()
+            .chain(&mut self.authorities)
+            .chain(&mut self.additionals);
+        for record in records.filter(|record| record.domain_name == *name) {
+            record.domain_name = name.clone();
+        }
+    }
+
+    pub fn encode(&self, encoder: &mut DnsEncoder) {
+        self.encode_with_boundaries(encoder, |_| {});
+    }
+
+    /// `encode`, calling `boundary` with the encoder's length before the first answer record and
+    /// after each answer, authority and additional record
+    fn encode_with_boundaries(&self, encoder: &mut DnsEncoder, mut boundary: impl FnMut(usize)) {
+        let header = DnsHeader {
+            question_count: self.questions.len() as u16,
+            answer_record_count: self.answers.len() as u16,
+            authority_record_count: self.authorities.len() as u16,
+            additional_record_count: (self.additionals.len() + self.edns.is_some() as usize) as u16,
+            ..self.header
+        };
+        encoder.write_bytes(&header.to_network_bytes());
+        for question in &self.questions {
+            question.encode(encoder);
+        }
+        boundary(encoder.len());
+        for record in self
+            .answers
+            .iter()
+            .chain(&self.authorities)
+            .chain(&self.additionals)
+        {
+            record.encode(encoder);
+            boundary(encoder.len());
+        }
+        if let Some(edns) = &self.edns {
+            let extended_rcode = (u16::from(self.header.response_code) >> 4) as u8;
+            edns.to_record(extended_rcode).encode(encoder);
+        }
+    }
+}
+
+fn decode_records(
+    buf: &[u8],
+    offset: &mut usize,
+    count: u16,
+    section: &'static str,
+) -> Result<Vec<ResourceRecord>, DecodeError> {
+    (0..count)
+        .map(|_| decode_record(buf, offset, count, section))
+        .collect()
+}
+
+fn decode_record(
+    buf: &[u8],
+    offset: &mut usize,
+    count: u16,
+    section: &'static str,
+) -> Result<ResourceRecord, DecodeError> {
+    if *offset == buf.len() {
+        return Err(DecodeError::BadCount {
+            offset: *offset,
+            section,
+            count,
+        });
+    }
+    let (record, len) = ResourceRecord::from_bytes(buf, *offset)?;
+    *offset += len;
+    Ok(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_protocol::{
+        dns_field_codes::{RecordClass, RecordType},
+        dns_name::DomainName,
+        dns_resource_record::RData,
+    };
+    use std::net::Ipv4Addr;
+
+    fn example_com() -> DomainName {
+        "example.com".parse().unwrap()
+    }
+
+    #[test]
+    fn test_to_from_bytes() {
+        let message = DnsMessage {
+            header: DnsHeader {
+                packet_identifier: 0xbeef,
+                query_response_indicator: true,
+                recursion_desired: true,
+                ..Default::default()
+            },
+            questions: vec![DnsQuestion {
+                domain_name: example_com(),
+                question_type: RecordType::A,
+                class: RecordClass::In,
+            }],
+            answers: vec![
+                ResourceRecord::new(
+                    example_com(),
+                    RecordType::A,
+                    RecordClass::In,
+                    60,
+                    RData::A(Ipv4Addr::new(93, 184, 216, 34)),
+                ),
+                ResourceRecord::new(
+                    example_com(),
+                    RecordType::A,
+                    RecordClass::In,
+                    60,
+                    RData::A(Ipv4Addr::new(93, 184, 216, 35)),
+                ),
+            ],
+            authorities: vec![ResourceRecord::new(
+                example_com(),
+                RecordType::Ns,
+                RecordClass::In,
+                3600,
+                RData::Ns(example_com()),
+            )],
+            additionals: vec![ResourceRecord::new(
+                example_com(),
+                RecordType::Txt,
+                RecordClass::In,
+                30,
+                RData::Txt(vec![]),
+            )],
+            edns: None,
+        };
+        let bytes = message.to_bytes();
+        let decoded = DnsMessage::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.header.answer_record_count, 2);
+        assert_eq!(decoded.header.authority_record_count, 1);
+        assert_eq!(decoded.header.additional_record_count, 1);
+        assert_eq!(decoded.questions, message.questions);
+        assert_eq!(decoded.answers, message.answers);
+        assert_eq!(decoded.authorities, message.authorities);
+        assert_eq!(decoded.additionals, message.additionals);
+    }
+
+    #[test]
+    fn test_edns_to_from_bytes() {
+        let message = DnsMessage {
+            header: DnsHeader {
+                query_response_indicator: true,
+                response_code: ResponseCode::BadVersion,
+                ..Default::default()
+            },
+            questions: vec![DnsQuestion {
+                domain_name: example_com(),
+                question_type: RecordType::A,
+                class: RecordClass::In,
+            }],
+            additionals: vec![ResourceRecord::new(
+                example_com(),
+                RecordType::Txt,
+                RecordClass::In,
+                30,
+                RData::Txt(vec![b"hi".to_vec()]),
+            )],
+            edns: Some(Edns {
+                udp_payload_size: 1232,
+                dnssec_ok: true,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let bytes = message.to_compressed_bytes();
+        // 16 doesn't fit in the header's four bits; the rest goes in the OPT TTL
+        assert_eq!(bytes[3] & 0x0F, 0);
+        assert_eq!(u16::from_be_bytes([bytes[10], bytes[11]]), 2);
+        let decoded = DnsMessage::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.header.response_code, ResponseCode::BadVersion);
+        assert_eq!(decoded.additionals, message.additionals);
+        assert_eq!(decoded.edns, message.edns);
+
+        let mut duplicated = bytes.clone();
+        duplicated[11] = 3;
+        duplicated.extend_from_slice(&bytes[bytes.len() - 11..]);
+        assert_eq!(
+            DnsMessage::from_bytes(&duplicated),
+            Err(DecodeError::DuplicateOpt {
+                offset: bytes.len()
+            })
+        );
+    }
+
+    #[test]
+    fn test_from_bytes_truncated() {
+        let bytes = DnsMessage {
+            questions: vec![DnsQuestion {
+                domain_name: example_com(),
+                question_type: RecordType::A,
+                class: RecordClass::In,
+            }],
+            answers: vec![ResourceRecord::new(
+                example_com(),
+                RecordType::A,
+                RecordClass::In,
+                60,
+                RData::A(Ipv4Addr::new(1, 2, 3, 4)),
+            )],
+            ..Default::default()
+        }
+        .to_bytes();
+        assert_eq!(
+            DnsMessage::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(DecodeError::Truncated {
+                offset: bytes.len() - 1
+            })
+        );
+        assert_eq!(
+            DnsMessage::from_bytes(&bytes[..DNS_HEADER_SIZE - 1]),
+            Err(DecodeError::Truncated {
+                offset: DNS_HEADER_SIZE - 1
+            })
+        );
+        let mut extra_answer = bytes.clone();
+        extra_answer[7] = 2;
+        assert_eq!(
+            DnsMessage::from_bytes(&extra_answer),
+            Err(DecodeError::BadCount {
+                offset: bytes.len(),
+                section: "answer",
+                count: 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_to_compressed_bytes() {
+        let message = DnsMessage {
+            questions: vec![DnsQuestion {
+                domain_name: example_com(),
+                question_type: RecordType::Mx,
+                class: RecordClass::In,
+            }],
+            answers: vec![ResourceRecord::new(
+                example_com(),
+                RecordType::Mx,
+                RecordClass::In,
+                60,
+                RData::Mx {
+                    preference: 10,
+                    exchange: example_com(),
+                },
+            )],
+            ..Default::default()
+        };
+        let bytes = message.to_compressed_bytes();
+        assert_eq!(bytes.len(), message.to_bytes().len() - 2 * 11);
+        assert_eq!(
+            DnsMessage::from_bytes(&bytes).unwrap().answers,
+            message.answers
+        );
+        assert_eq!(
+            bytes[DNS_HEADER_SIZE + 17..],
+            [0xC0, 12, 0, 15, 0, 1, 0, 0, 0, 60, 0, 4, 0, 10, 0xC0, 12]
+        );
+    }
+
+    #[test]
+    fn test_to_truncated_bytes() {
+        let a_record = |last_octet| {
+            ResourceRecord::new(
+                example_com(),
+                RecordType::A,
+                RecordClass::In,
+                60,
+                RData::A(Ipv4Addr::new(192, 0, 2, last_octet)),
+            )
+        };
+        let message = DnsMessage {
+            questions: vec![DnsQuestion {
+                domain_name: example_com(),
+                question_type: RecordType::A,
+                class: RecordClass::In,
+            }],
+            answers: (0..40).map(a_record).collect(),
+            additionals: vec![a_record(255)],
+            edns: Some(Edns::default()),
+            ..Default::default()
+        };
+        // 12 header + 17 question + 11 OPT leaves room for 29 16-byte A records in 512 bytes
+        let bytes = message.to_truncated_bytes(512);
+        assert!(bytes.len() <= 512);
+        let decoded = DnsMessage::from_bytes(&bytes).unwrap();
+        assert!(decoded.header.truncation);
+        assert_eq!(decoded.answers[..], message.answers[..29]);
+        assert!(decoded.additionals.is_empty());
+        assert_eq!(decoded.edns, message.edns);
+
+        // Dropping only additional records doesn't set TC
+        let bytes = message.to_truncated_bytes(message.to_compressed_bytes().len() - 1);
+        let decoded = DnsMessage::from_bytes(&bytes).unwrap();
+        assert!(!decoded.header.truncation);
+        assert_eq!(decoded.answers, message.answers);
+        assert!(decoded.additionals.is_empty());
+
+        assert_eq!(
+            message.to_truncated_bytes(4096),
+            message.to_compressed_bytes()
+        );
+    }
+
+    #[test]
+    fn test_to_truncated_bytes_large_rrset() {
+        let message = DnsMessage {
+            header: DnsHeader {
+                query_response_indicator: true,
+                ..Default::default()
+            },
+            questions: vec![DnsQuestion {
+                domain_name: example_com(),
+                question_type: RecordType::A,
+                class: RecordClass::In,
+            }],
+            answers: (0..4000u32)
+                .map(|n| {
+                    ResourceRecord::new(
+                        example_com(),
+                        RecordType::A,
+                        RecordClass::In,
+                        60,
+                        RData::A(Ipv4Addr::from(0xC000_0200 + n)),
+                    )
+                })
+                .collect(),
+            edns: Some(Edns::default()),
+            ..Default::default()
+        };
+        // Encoded once rather than once per dropped record, so this is quick even in debug builds
+        let bytes = message.to_truncated_bytes(512);
+        assert!(bytes.len() <= 512);
+        let decoded = DnsMessage::from_bytes(&bytes).unwrap();
+        assert!(decoded.header.truncation);
+        assert!(decoded.header.query_response_indicator);
+        assert_eq!(decoded.answers[..], message.answers[..29]);
+        assert_eq!(decoded.edns, message.edns);
+
+        // Not even the question and OPT fit: all the records go
+        let bytes = message.to_truncated_bytes(20);
+        let decoded = DnsMessage::from_bytes(&bytes).unwrap();
+        assert!(decoded.header.truncation);
+        assert_eq!(decoded.questions, message.questions);
+        assert!(decoded.answers.is_empty());
+        assert_eq!(decoded.edns, message.edns);
+    }
+}
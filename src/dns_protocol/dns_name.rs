@@ -0,0 +1,483 @@
+use crate::dns_protocol::dns_error::{DecodeError, ParseNameError};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+
+pub const MAX_LABEL_LENGTH: usize = 63;
+/// Wire-format length including every length octet and the root label
+pub const MAX_NAME_LENGTH: usize = 255;
+/// A legitimate name can't need more pointers than it has labels, and it can't have more than
+/// 127 labels, so anything past this is a loop or an attempt to make us spin.
+const MAX_POINTER_HOPS: usize = 127;
+
+/// A single non-empty label of at most 63 arbitrary bytes. Equality is exact; use
+/// `eq_ignore_case` (or compare whole `DomainName`s) for DNS semantics.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Label(Vec<u8>);
+
+impl Label {
+    pub fn new(content: &[u8]) -> Result<Self, ParseNameError> {
+        if content.is_empty() {
+            return Err(ParseNameError::EmptyLabel);
+        }
+        if content.len() > MAX_LABEL_LENGTH {
+            return Err(ParseNameError::LabelTooLong {
+                length: content.len(),
+            });
+        }
+        Ok(Self(content.to_vec()))
+    }
+
+    fn from_wire(bytes: &[u8]) -> Self {
+        Self(bytes.to_vec())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn eq_ignore_case(&self, other: &Label) -> bool {
+        self.0.eq_ignore_ascii_case(&other.0)
+    }
+}
+
+/// Presentation format: `.` and `\` are backslash-escaped and anything outside printable ASCII
+/// is written as `\DDD`.
+impl fmt::Display for Label {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for &byte in &self.0 {
+            match byte {
+                b'.' | b'\\' => write!(f, "\\{}", byte as char)?,
+                b'!'..=b'~' => write!(f, "{}", byte as char)?,
+                _ => write!(f, "\\{:03}", byte)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A validated domain name. Comparison and hashing ignore ASCII case, as RFC 4343 requires.
+#[derive(Debug, Clone, Default)]
+pub struct DomainName {
+    labels: Vec<Label>,
+}
+
+impl DomainName {
+    pub fn root() -> Self {
+        Self::default()
+    }
+
+    pub fn from_labels(labels: Vec<Label>) -> Result<Self, ParseNameError> {
+        let name = Self { labels };
+        if name.wire_len() > MAX_NAME_LENGTH {
+            return Err(ParseNameError::NameTooLong);
+        }
+        Ok(name)
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    pub fn label_count(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn iter_labels(&self) -> impl Iterator<Item = &Label> {
+        self.labels.iter()
+    }
+
+    /// Length of the uncompressed wire encoding, including the root label
+    pub fn wire_len(&self) -> usize {
+        self.labels
+            .iter()
+            .map(|label| 1 + label.len())
+            .sum::<usize>()
+            + 1
+    }
+
+    /// The name with its leftmost label removed, or `None` for the root
+    pub fn parent(&self) -> Option<DomainName> {
+        if self.is_root() {
+            return None;
+        }
+        Some(Self {
+            labels: self.labels[1..].to_vec(),
+        })
+    }
+
+    /// The names made of each suffix of this one, starting with the full name and ending just
+    /// before the root.
+    pub fn suffixes(&self) -> impl Iterator<Item = DomainName> + '_ {
+        (0..self.labels.len()).map(|i| Self {
+            labels: self.labels[i..].to_vec(),
+        })
+    }
+
+    /// True if this name is `other` or lies beneath it
+    pub fn is_subdomain_of(&self, other: &DomainName) -> bool {
+        self.labels.len() >= other.labels.len()
+            && self.labels[self.labels.len() - other.labels.len()..]
+                .iter()
+                .zip(&other.labels)
+                .all(|(a, b)| a.eq_ignore_case(b))
+    }
+
+    /// Byte-for-byte comparison, for when the casing matters (e.g. DNS 0x20). `==` ignores case.
+    pub fn eq_case_sensitive(&self, other: &DomainName) -> bool {
+        self.labels == other.labels
+    }
+}
+
+impl PartialEq for DomainName {
+    fn eq(&self, other: &Self) -> bool {
+        self.labels.len() == other.labels.len()
+            && self
+                .labels
+                .iter()
+                .zip(&other.labels)
+                .all(|(a, b)| a.eq_ignore_case(b))
+    }
+}
+
+impl Eq for DomainName {}
+
+impl Hash for DomainName {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for label in &self.labels {
+            state.write_u8(label.len() as u8);
+            for byte in label.as_bytes() {
+                state.write_u8(byte.to_ascii_lowercase());
+            }
+        }
+        state.write_u8(0);
+    }
+}
+
+impl fmt::Display for DomainName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_root() {
+            return f.write_str(".");
+        }
+        for label in &self.labels {
+            write!(f, "{}.", label)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses presentation format. The trailing dot is optional and `\.`, `\\` and `\DDD`
+/// escapes are understood.
+impl FromStr for DomainName {
+    type Err = ParseNameError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        if text == "." {
+            return Ok(Self::root());
+        }
+        let mut labels = Vec::<Label>::new();
+        let mut current = Vec::<u8>::new();
+        let mut bytes = text.bytes();
+        let mut ended_with_dot = false;
+        while let Some(byte) = bytes.next() {
+            ended_with_dot = false;
+            match byte {
+                b'.' => {
+                    labels.push(Label::new(&current)?);
+                    current.clear();
+                    ended_with_dot = true;
+                }
+                b'\\' => match bytes.next() {
+                    Some(digit @ b'0'..=b'9') => {
+                        let mut value = (digit - b'0') as u16;
+                        for _ in 0..2 {
+                            match bytes.next() {
+                                Some(digit @ b'0'..=b'9') => {
+                                    value = value * 10 + (digit - b'0') as u16
+                                }
+                                _ => return Err(ParseNameError::BadEscape),
+                            }
+                        }
+                        current.push(u8::try_from(value).map_err(|_| ParseNameError::BadEscape)?);
+                    }
+                    Some(escaped) => current.push(escaped),
+                    None => return Err(ParseNameError::BadEscape),
+                },
+                _ => current.push(byte),
+            }
+        }
+        if !ended_with_dot {
+            labels.push(Label::new(&current)?);
+        }
+        Self::from_labels(labels)
+    }
+}
+
+/// Reads the name at the absolute `offset` in `message`, following compression pointers.
+/// Returns the labels and the number of bytes the name occupies at `offset` (i.e. up to and
+/// including the first pointer).
+///
+/// Every pointer must land strictly before the start of the run of labels it ends, so each hop
+/// moves further back in the message. That rules out forward references and loops on its own;
+/// the hop limit is a second line of defence.
+pub fn decode_name(message: &[u8], offset: usize) -> Result<(DomainName, usize), DecodeError> {
+    let mut labels = Vec::<Label>::new();
+    let mut position = offset;
+    let mut segment_start = offset;
+    let mut name_len = None;
+    let mut total_len = 1;
+    let mut hops = 0;
+    loop {
+        let length = byte_at(message, position)?;
+        match length & 0b11000000 {
+            0b11000000 => {
+                let pointer =
+                    u16::from_be_bytes([length & 0b00111111, byte_at(message, position + 1)?])
+                        as usize;
+                if pointer >= segment_start {
+                    return Err(DecodeError::BadPointer {
+                        offset: position,
+                        target: pointer,
+                    });
+                }
+                hops += 1;
+                if hops > MAX_POINTER_HOPS {
+                    return Err(DecodeError::PointerLoop { offset });
+                }
+                name_len.get_or_insert_with(|| position + 2 - offset);
+                position = pointer;
+                segment_start = pointer;
+            }
+            0 if length == 0 => {
+                name_len.get_or_insert_with(|| position + 1 - offset);
+                break;
+            }
+            0 => {
+                let length = length as usize;
+                total_len += 1 + length;
+                if total_len > MAX_NAME_LENGTH {
+                    return Err(DecodeError::NameTooLong { offset });
+                }
+                let content = message.get(position + 1..position + 1 + length).ok_or(
+                    DecodeError::Truncated {
+                        offset: message.len(),
+                    },
+                )?;
+                labels.push(Label::from_wire(content));
+                position += 1 + length;
+            }
+            // 0b01 and 0b10 prefixes are reserved, which is also what a length over 63 looks like
+            _ => {
+                return Err(DecodeError::BadLabelType {
+                    offset: position,
+                    label_type: length >> 6,
+                })
+            }
+        }
+    }
+    Ok((DomainName { labels }, name_len.unwrap_or_default()))
+}
+
+fn byte_at(message: &[u8], offset: usize) -> Result<u8, DecodeError> {
+    message
+        .get(offset)
+        .copied()
+        .ok_or(DecodeError::Truncated { offset })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_protocol::dns_encoder::DnsEncoder;
+
+    #[test]
+    fn test_decode_name_pointer_chain() {
+        let message = [
+            0xAA, 3, b'c', b'o', b'm', 0, 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0xC0, 1, 3,
+            b'w', b'w', b'w', 0xC0, 6,
+        ];
+        let (labels, len) = decode_name(&message, 16).unwrap();
+        assert_eq!(len, 6);
+        assert_eq!(labels.to_string(), "www.example.com.");
+    }
+
+    #[test]
+    fn test_decode_name_rejects_loops_and_forward_pointers() {
+        assert_eq!(
+            decode_name(&[0xC0, 0], 0),
+            Err(DecodeError::BadPointer {
+                offset: 0,
+                target: 0
+            })
+        );
+        assert_eq!(
+            decode_name(&[3, b'w', b'w', b'w', 0xC0, 0], 0),
+            Err(DecodeError::BadPointer {
+                offset: 4,
+                target: 0
+            })
+        );
+        assert_eq!(
+            decode_name(&[0xC0, 2, 0], 0),
+            Err(DecodeError::BadPointer {
+                offset: 0,
+                target: 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_decode_name_rejects_oversized() {
+        let mut label = vec![MAX_LABEL_LENGTH as u8 + 1];
+        label.extend([b'a'; MAX_LABEL_LENGTH + 1]);
+        label.push(0);
+        assert_eq!(
+            decode_name(&label, 0),
+            Err(DecodeError::BadLabelType {
+                offset: 0,
+                label_type: 0b01
+            })
+        );
+
+        let mut name = Vec::<u8>::new();
+        for _ in 0..4 {
+            name.push(63);
+            name.extend([b'a'; 63]);
+        }
+        name.push(0);
+        // 4 * 64 + 1 = 257 bytes
+        assert_eq!(
+            decode_name(&name, 0),
+            Err(DecodeError::NameTooLong { offset: 0 })
+        );
+        let mut short = name[..3 * 64].to_vec();
+        short.push(61);
+        short.extend([b'a'; 61]);
+        short.push(0);
+        assert_eq!(short.len(), MAX_NAME_LENGTH);
+        assert_eq!(decode_name(&short, 0).unwrap().1, MAX_NAME_LENGTH);
+    }
+
+    #[test]
+    fn test_decode_name_rejects_truncated_and_reserved() {
+        assert_eq!(
+            decode_name(&[3, b'w', b'w'], 0),
+            Err(DecodeError::Truncated { offset: 3 })
+        );
+        assert_eq!(
+            decode_name(&[0xC0], 0),
+            Err(DecodeError::Truncated { offset: 1 })
+        );
+        assert_eq!(
+            decode_name(&[0x40, 0], 0),
+            Err(DecodeError::BadLabelType {
+                offset: 0,
+                label_type: 0b01
+            })
+        );
+        assert!(decode_name(&[0x80, 0], 0).is_err());
+    }
+
+    #[test]
+    fn test_parse_display() {
+        let name: DomainName = "www.Example.com".parse().unwrap();
+        assert_eq!(name.to_string(), "www.Example.com.");
+        assert_eq!(name.label_count(), 3);
+        assert_eq!(name.wire_len(), 17);
+        assert_eq!("www.example.com.".parse::<DomainName>().unwrap(), name);
+        assert_eq!(".".parse::<DomainName>().unwrap(), DomainName::root());
+        assert_eq!(DomainName::root().to_string(), ".");
+
+        let escaped: DomainName = r"a\.b\\c\032d.example".parse().unwrap();
+        assert_eq!(escaped.label_count(), 2);
+        assert_eq!(
+            escaped.iter_labels().next().unwrap().as_bytes(),
+            b"a.b\\c d"
+        );
+        assert_eq!(escaped.to_string(), r"a\.b\\c\032d.example.");
+    }
+
+    #[test]
+    fn test_parse_rejects_invalid() {
+        assert_eq!(
+            "a..b".parse::<DomainName>(),
+            Err(ParseNameError::EmptyLabel)
+        );
+        assert_eq!("".parse::<DomainName>(), Err(ParseNameError::EmptyLabel));
+        assert_eq!(
+            "a".repeat(64).parse::<DomainName>(),
+            Err(ParseNameError::LabelTooLong { length: 64 })
+        );
+        let long = vec!["a".repeat(63); 4].join(".");
+        assert_eq!(long.parse::<DomainName>(), Err(ParseNameError::NameTooLong));
+        assert_eq!(
+            r"a\25".parse::<DomainName>(),
+            Err(ParseNameError::BadEscape)
+        );
+        assert_eq!(
+            r"a\256".parse::<DomainName>(),
+            Err(ParseNameError::BadEscape)
+        );
+        assert_eq!(
+            r"a\2x5".parse::<DomainName>(),
+            Err(ParseNameError::BadEscape)
+        );
+    }
+
+    #[test]
+    fn test_binary_labels_round_trip() {
+        // Label bytes >= 0x80 must stay single bytes rather than turning into UTF-8 sequences
+        let wire = [2, 0xFF, 0x80, 3, b'c', b'o', b'm', 0];
+        let (name, len) = decode_name(&wire, 0).unwrap();
+        assert_eq!(len, wire.len());
+        assert_eq!(name.wire_len(), wire.len());
+        assert_eq!(name.iter_labels().next().unwrap().as_bytes(), [0xFF, 0x80]);
+        assert_eq!(name.to_string(), r"\255\128.com.");
+
+        let mut encoder = DnsEncoder::uncompressed();
+        encoder.write_name(&name);
+        assert_eq!(encoder.into_bytes(), wire);
+
+        let parsed: DomainName = name.to_string().parse().unwrap();
+        assert_eq!(
+            parsed.iter_labels().next().unwrap().as_bytes(),
+            [0xFF, 0x80]
+        );
+    }
+
+    #[test]
+    fn test_case_insensitive_eq_hash() {
+        use std::collections::HashSet;
+        let lower: DomainName = "www.example.com".parse().unwrap();
+        let upper: DomainName = "WWW.EXAMPLE.COM".parse().unwrap();
+        assert_eq!(lower, upper);
+        assert!(!lower.eq_case_sensitive(&upper));
+        assert!(upper.eq_case_sensitive(&"WWW.EXAMPLE.COM".parse().unwrap()));
+        let set = HashSet::from([lower]);
+        assert!(set.contains(&upper));
+        assert_ne!(upper, "www.example.org".parse().unwrap());
+    }
+
+    #[test]
+    fn test_hierarchy() {
+        let name: DomainName = "www.example.com".parse().unwrap();
+        let parent = name.parent().unwrap();
+        assert_eq!(parent.to_string(), "example.com.");
+        assert!(name.is_subdomain_of(&parent));
+        assert!(name.is_subdomain_of(&name));
+        assert!(name.is_subdomain_of(&"COM".parse().unwrap()));
+        assert!(name.is_subdomain_of(&DomainName::root()));
+        assert!(!parent.is_subdomain_of(&name));
+        assert!(!name.is_subdomain_of(&"ample.com".parse().unwrap()));
+        assert_eq!(DomainName::root().parent(), None);
+        assert_eq!(name.suffixes().count(), 3);
+    }
+}
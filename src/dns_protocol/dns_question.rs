@@ -1,93 +1,63 @@
-#[derive(Debug)]
-pub struct Label {
-    pub length: u8,
-    pub content: String,
-}
+use crate::dns_protocol::{
+    dns_encoder::DnsEncoder,
+    dns_error::DecodeError,
+    dns_field_codes::{RecordClass, RecordType},
+    dns_name::{decode_name, DomainName},
+};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct DnsQuestion {
-    pub domain_name: Vec<Label>,
-    pub question_type: u16,
-    pub class: u16,
+    pub domain_name: DomainName,
+    pub question_type: RecordType,
+    pub class: RecordClass,
 }
 
 impl DnsQuestion {
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::<u8>::new();
-        for label in &self.domain_name {
-            bytes.push(label.length);
-            bytes.extend(label.content.bytes());
-        }
-        bytes.push(0);
-        bytes.push((self.question_type >> 8) as u8);
-        bytes.push(self.question_type as u8);
-        bytes.push((self.class >> 8) as u8);
-        bytes.push(self.class as u8);
-        bytes
+        let mut encoder = DnsEncoder::uncompressed();
+        self.encode(&mut encoder);
+        encoder.into_bytes()
+    }
+
+    pub fn encode(&self, encoder: &mut DnsEncoder) {
+        encoder.write_name(&self.domain_name);
+        encoder.write_u16(self.question_type.into());
+        encoder.write_u16(self.class.into());
     }
 }
 
-pub fn decode_questions(buf: &[u8], number_of_questions: u16) -> Option<Vec<DnsQuestion>> {
-    //todo lots of copying going on here
-    println!("Parsing {} questions", number_of_questions);
+/// Decodes `number_of_questions` questions starting at the absolute `offset` in `message`,
+/// returning them along with the number of bytes they occupy.
+pub fn decode_questions(
+    message: &[u8],
+    offset: usize,
+    number_of_questions: u16,
+) -> Result<(Vec<DnsQuestion>, usize), DecodeError> {
     let mut questions = Vec::<DnsQuestion>::new();
-    let mut label_iter = buf.iter();
-    let mut end_of_label_iter = buf.iter();
+    let mut position = offset;
     for _ in 0..number_of_questions {
-        let mut labels = Vec::<Label>::new();
-        let mut is_compressed = false;
-        while let Some(length) = label_iter.next() {
-            if *length == 0x00 {
-                // iter.next();
-                if is_compressed {
-                    end_of_label_iter.next();
-                }
-                end_of_label_iter.next()?;
-                break;
-            }
-            let (content_len, content) = if *length & 0b11000000 != 0 {
-                is_compressed = true;
-                let offset =
-                    (((*length & 0b00111111) as u16) << 8 | *label_iter.next()? as u16) - 12;
-                label_iter = buf.iter();
-                label_iter.nth(offset as usize - 1);
-                let label_len = *label_iter.next()?;
-                let label = label_iter
-                    .clone()
-                    .take(label_len as usize)
-                    .map(|&x| x as char)
-                    .collect();
-                label_iter.nth(label_len as usize - 1);
-                (label_len, label)
-            } else {
-                let content = label_iter
-                    .clone()
-                    .take(*length as usize)
-                    .map(|&x| x as char)
-                    .collect();
-                label_iter.nth(*length as usize - 1);
-                (*length, content)
-            };
-            if !is_compressed {
-                end_of_label_iter.nth(*length as usize).unwrap();
-            }
-            println!("content_len: {}, content: {}", content_len, content);
-            labels.push(Label {
-                length: content_len,
-                content,
+        if position == message.len() {
+            return Err(DecodeError::BadCount {
+                offset: position,
+                section: "question",
+                count: number_of_questions,
             });
         }
-        let question_type =
-            u16::from_be_bytes([*end_of_label_iter.next()?, *end_of_label_iter.next()?]);
-        let class = u16::from_be_bytes([*end_of_label_iter.next()?, *end_of_label_iter.next()?]);
+        let (domain_name, name_len) = decode_name(message, position)?;
+        position += name_len;
+        let fixed = message
+            .get(position..position + 4)
+            .ok_or(DecodeError::Truncated {
+                offset: message.len(),
+            })?;
         questions.push(DnsQuestion {
-            domain_name: labels,
-            question_type, //todo need to move this out but at end of all questions?????
-            class,
+            domain_name,
+            question_type: u16::from_be_bytes([fixed[0], fixed[1]]).into(),
+            class: u16::from_be_bytes([fixed[2], fixed[3]]).into(),
         });
-        label_iter.nth(3);
+        position += 4;
     }
-    Some(questions)
+    Ok((questions, position - offset))
 }
 
 #[cfg(test)]
@@ -97,51 +67,71 @@ mod tests {
     #[test]
     fn test_decode_questions() {
         let buf = [
-            0x03, 0x77, 0x77, 0x77, 0x07, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x03, 0x63,
-            0x6f, 0x6d, 0x00, 0x00, 0x01, 0x00, 0x01,
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x03, 0x77, 0x77, 0x77, 0x07, 0x65, 0x78, 0x61,
+            0x6d, 0x70, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00, 0x00, 0x01, 0x00, 0x01,
         ];
-        let questions = decode_questions(&buf, 1).unwrap();
+        let (questions, len) = decode_questions(&buf, 12, 1).unwrap();
+        assert_eq!(len, buf.len() - 12);
         assert_eq!(questions.len(), 1);
         let question = &questions[0];
-        assert_eq!(question.domain_name.len(), 3);
-        assert_eq!(question.domain_name[0].length, 3);
-        assert_eq!(question.domain_name[0].content, "www");
-        assert_eq!(question.domain_name[1].length, 7);
-        assert_eq!(question.domain_name[1].content, "example");
-        assert_eq!(question.domain_name[2].length, 3);
-        assert_eq!(question.domain_name[2].content, "com");
-        assert_eq!(question.question_type, 1);
-        assert_eq!(question.class, 1);
+        let labels = question.domain_name.iter_labels().collect::<Vec<_>>();
+        assert_eq!(labels.len(), 3);
+        assert_eq!(labels[0].len(), 3);
+        assert_eq!(labels[0].to_string(), "www");
+        assert_eq!(labels[1].len(), 7);
+        assert_eq!(labels[1].to_string(), "example");
+        assert_eq!(labels[2].len(), 3);
+        assert_eq!(labels[2].to_string(), "com");
+        assert_eq!(question.question_type, RecordType::A);
+        assert_eq!(question.class, RecordClass::In);
     }
 
     #[test]
     fn test_compressed_questions_middle() {
         let buf = [
-            3, 97, 98, 99, 17, 108, 111, 110, 103, 97, 115, 115, 100, 111, 109, 97, 105, 110, 110,
-            97, 109, 101, 3, 99, 111, 109, 0, 0, 1, 0, 1, 3, 100, 101, 102, 192, 16, 0, 1, 0, 1,
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 3, 97, 98, 99, 17, 108, 111, 110, 103, 97, 115,
+            115, 100, 111, 109, 97, 105, 110, 110, 97, 109, 101, 3, 99, 111, 109, 0, 0, 1, 0, 1, 3,
+            100, 101, 102, 192, 16, 0, 1, 0, 1,
         ];
-        let questions = decode_questions(&buf, 2).unwrap();
+        let (questions, len) = decode_questions(&buf, 12, 2).unwrap();
+        assert_eq!(len, buf.len() - 12);
         assert_eq!(questions.len(), 2);
         let mut question = &questions[0];
-        assert_eq!(question.domain_name.len(), 3);
-        assert_eq!(question.domain_name[0].length, 3);
-        assert_eq!(question.domain_name[0].content, "abc");
-        assert_eq!(question.domain_name[1].length, 17);
-        assert_eq!(question.domain_name[1].content, "longassdomainname");
-        assert_eq!(question.domain_name[2].length, 3);
-        assert_eq!(question.domain_name[2].content, "com");
-        assert_eq!(question.question_type, 1);
-        assert_eq!(question.class, 1);
+        let labels = question.domain_name.iter_labels().collect::<Vec<_>>();
+        assert_eq!(labels.len(), 3);
+        assert_eq!(labels[0].len(), 3);
+        assert_eq!(labels[0].to_string(), "abc");
+        assert_eq!(labels[1].len(), 17);
+        assert_eq!(labels[1].to_string(), "longassdomainname");
+        assert_eq!(labels[2].len(), 3);
+        assert_eq!(labels[2].to_string(), "com");
+        assert_eq!(question.question_type, RecordType::A);
+        assert_eq!(question.class, RecordClass::In);
 
         question = &questions[1];
-        assert_eq!(question.domain_name.len(), 3);
-        assert_eq!(question.domain_name[0].length, 3);
-        assert_eq!(question.domain_name[0].content, "def");
-        assert_eq!(question.domain_name[1].length, 17);
-        assert_eq!(question.domain_name[1].content, "longassdomainname");
-        assert_eq!(question.domain_name[2].length, 3);
-        assert_eq!(question.domain_name[2].content, "com");
-        assert_eq!(question.question_type, 1);
-        assert_eq!(question.class, 1);
+        let labels = question.domain_name.iter_labels().collect::<Vec<_>>();
+        assert_eq!(labels.len(), 3);
+        assert_eq!(labels[0].len(), 3);
+        assert_eq!(labels[0].to_string(), "def");
+        assert_eq!(labels[1].len(), 17);
+        assert_eq!(labels[1].to_string(), "longassdomainname");
+        assert_eq!(labels[2].len(), 3);
+        assert_eq!(labels[2].to_string(), "com");
+        assert_eq!(question.question_type, RecordType::A);
+        assert_eq!(question.class, RecordClass::In);
+    }
+
+    #[test]
+    fn test_compressed_question_first_then_literal() {
+        // The pointer in the first question must not throw off where the second one starts
+        let buf = [
+            3, 99, 111, 109, 0, 0, 0, 0, 0, 0, 0, 0, 3, 119, 119, 119, 192, 0, 0, 1, 0, 1, 3, 111,
+            114, 103, 0, 0, 28, 0, 1,
+        ];
+        let (questions, len) = decode_questions(&buf, 12, 2).unwrap();
+        assert_eq!(len, buf.len() - 12);
+        assert_eq!(questions[0].domain_name.to_string(), "www.com.");
+        assert_eq!(questions[1].domain_name.to_string(), "org.");
+        assert_eq!(questions[1].question_type, RecordType::Aaaa);
     }
 }
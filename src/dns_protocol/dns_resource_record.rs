@@ -1,88 +1,452 @@
-use crate::dns_protocol::dns_question::Label;
+use crate::dns_protocol::{
+    dns_edns::EdnsOption,
+    dns_encoder::DnsEncoder,
+    dns_error::DecodeError,
+    dns_field_codes::{RecordClass, RecordType},
+    dns_name::{decode_name, DomainName},
+};
+use std::net::{Ipv4Addr, Ipv6Addr};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum RData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Ns(DomainName),
+    Cname(DomainName),
+    Ptr(DomainName),
+    Mx {
+        preference: u16,
+        exchange: DomainName,
+    },
+    Txt(Vec<Vec<u8>>),
+    Soa {
+        mname: DomainName,
+        rname: DomainName,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+    },
+    Srv {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: DomainName,
+    },
+    Caa {
+        flags: u8,
+        tag: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Opt(Vec<EdnsOption>),
+    Unknown(Vec<u8>),
+}
+
+impl RData {
+    /// Decodes the `len` bytes at `offset` in `message` as the RDATA of a record of type
+    /// `record_type`. The whole message is needed because names inside RDATA may be compressed.
+    /// Types we don't know about are kept verbatim as `Unknown`; known types that don't parse
+    /// cleanly are rejected.
+    pub fn from_bytes(
+        record_type: RecordType,
+        message: &[u8],
+        offset: usize,
+        len: usize,
+    ) -> Result<Self, DecodeError> {
+        let end = offset + len;
+        if end > message.len() {
+            return Err(DecodeError::Truncated {
+                offset: message.len(),
+            });
+        }
+        let mut reader = RDataReader {
+            message,
+            start: offset,
+            offset,
+            end,
+            record_type,
+        };
+        let rdata = match record_type {
+            RecordType::A => RData::A(Ipv4Addr::from(reader.array::<4>()?)),
+            RecordType::Ns => RData::Ns(reader.name()?),
+            RecordType::Cname => RData::Cname(reader.name()?),
+            RecordType::Soa => RData::Soa {
+                mname: reader.name()?,
+                rname: reader.name()?,
+                serial: reader.u32()?,
+                refresh: reader.u32()?,
+                retry: reader.u32()?,
+                expire: reader.u32()?,
+                minimum: reader.u32()?,
+            },
+            RecordType::Ptr => RData::Ptr(reader.name()?),
+            RecordType::Mx => RData::Mx {
+                preference: reader.u16()?,
+                exchange: reader.name()?,
+            },
+            RecordType::Txt => {
+                let mut strings = Vec::<Vec<u8>>::new();
+                while reader.offset < end {
+                    let len = reader.array::<1>()?[0] as usize;
+                    strings.push(reader.bytes(len)?.to_vec());
+                }
+                RData::Txt(strings)
+            }
+            RecordType::Aaaa => RData::Aaaa(Ipv6Addr::from(reader.array::<16>()?)),
+            RecordType::Srv => RData::Srv {
+                priority: reader.u16()?,
+                weight: reader.u16()?,
+                port: reader.u16()?,
+                target: reader.name()?,
+            },
+            RecordType::Caa => {
+                let flags = reader.array::<1>()?[0];
+                let tag_len = reader.array::<1>()?[0] as usize;
+                let tag = reader.bytes(tag_len)?.to_vec();
+                let value = reader.bytes(end - reader.offset)?.to_vec();
+                RData::Caa { flags, tag, value }
+            }
+            RecordType::Opt => {
+                let mut options = Vec::<EdnsOption>::new();
+                while reader.offset < end {
+                    let code = reader.u16()?;
+                    let len = reader.u16()? as usize;
+                    let data = reader.bytes(len)?.to_vec();
+                    options.push(EdnsOption { code, data });
+                }
+                RData::Opt(options)
+            }
+            _ => RData::Unknown(reader.bytes(len)?.to_vec()),
+        };
+        if reader.offset != end {
+            return Err(reader.error());
+        }
+        Ok(rdata)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut encoder = DnsEncoder::uncompressed();
+        self.encode(&mut encoder);
+        encoder.into_bytes()
+    }
+
+    /// Names are only compressed for the RFC 1035 types, as RFC 3597 requires.
+    pub fn encode(&self, encoder: &mut DnsEncoder) {
+        match self {
+            RData::A(address) => encoder.write_bytes(&address.octets()),
+            RData::Aaaa(address) => encoder.write_bytes(&address.octets()),
+            RData::Ns(name) | RData::Cname(name) | RData::Ptr(name) => encoder.write_name(name),
+            RData::Mx {
+                preference,
+                exchange,
+            } => {
+                encoder.write_u16(*preference);
+                encoder.write_name(exchange);
+            }
+            RData::Txt(strings) => {
+                for string in strings {
+                    encoder.write_bytes(&[string.len() as u8]);
+                    encoder.write_bytes(string);
+                }
+            }
+            RData::Soa {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => {
+                encoder.write_name(mname);
+                encoder.write_name(rname);
+                for value in [serial, refresh, retry, expire, minimum] {
+                    encoder.write_u32(*value);
+                }
+            }
+            RData::Srv {
+                priority,
+                weight,
+                port,
+                target,
+            } => {
+                encoder.write_u16(*priority);
+                encoder.write_u16(*weight);
+                encoder.write_u16(*port);
+                encoder.write_uncompressed_name(target);
+            }
+            RData::Caa { flags, tag, value } => {
+                encoder.write_bytes(&[*flags, tag.len() as u8]);
+                encoder.write_bytes(tag);
+                encoder.write_bytes(value);
+            }
+            RData::Opt(options) => {
+                for option in options {
+                    encoder.write_u16(option.code);
+                    encoder.write_u16(option.data.len() as u16);
+                    encoder.write_bytes(&option.data);
+                }
+            }
+            RData::Unknown(data) => encoder.write_bytes(data),
+        }
+    }
+}
+
+struct RDataReader<'a> {
+    message: &'a [u8],
+    start: usize,
+    offset: usize,
+    end: usize,
+    record_type: RecordType,
+}
+
+impl<'a> RDataReader<'a> {
+    fn error(&self) -> DecodeError {
+        DecodeError::BadRData {
+            offset: self.start,
+            record_type: self.record_type,
+        }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        let end = self.offset + len;
+        if end > self.end {
+            return Err(self.error());
+        }
+        let bytes = &self.message[self.offset..end];
+        self.offset = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        Ok(self.bytes(N)?.try_into().expect("slice has length N"))
+    }
+
+    fn u16(&mut self) -> Result<u16, DecodeError> {
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+
+    fn name(&mut self) -> Result<DomainName, DecodeError> {
+        let (name, len) = decode_name(self.message, self.offset)?;
+        self.offset += len;
+        if self.offset > self.end {
+            return Err(self.error());
+        }
+        Ok(name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct ResourceRecord {
-    pub domain_name: Vec<Label>,
-    pub answer_type: u16,
-    pub class: u16,
+    pub domain_name: DomainName,
+    pub answer_type: RecordType,
+    pub class: RecordClass,
     pub ttl: u32,
-    pub data_length: u16,
-    pub data: Vec<u8>,
+    pub data: RData,
 }
 
 impl ResourceRecord {
     pub fn new(
-        domain_name: Vec<Label>,
-        answer_type: u16,
-        class: u16,
+        domain_name: DomainName,
+        answer_type: RecordType,
+        class: RecordClass,
         ttl: u32,
-        data: Vec<u8>,
+        data: RData,
     ) -> Self {
         Self {
             domain_name,
             answer_type,
             class,
             ttl,
-            data_length: data.len() as u16,
             data,
         }
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::<u8>::new();
-        for label in &self.domain_name {
-            bytes.push(label.length);
-            bytes.extend(label.content.bytes());
+        let mut encoder = DnsEncoder::uncompressed();
+        self.encode(&mut encoder);
+        encoder.into_bytes()
+    }
+
+    pub fn encode(&self, encoder: &mut DnsEncoder) {
+        encoder.write_name(&self.domain_name);
+        encoder.write_u16(self.answer_type.into());
+        encoder.write_u16(self.class.into());
+        encoder.write_u32(self.ttl);
+        let data_length_offset = encoder.len();
+        encoder.write_u16(0);
+        self.data.encode(encoder);
+        let data_length = encoder.len() - data_length_offset - 2;
+        encoder.set_u16(data_length_offset, data_length as u16);
+    }
+
+    /// Decodes the record starting at `offset` in `message`, returning it along with the number
+    /// of bytes it occupies.
+    pub fn from_bytes(message: &[u8], offset: usize) -> Result<(Self, usize), DecodeError> {
+        let (labels, name_len) = decode_name(message, offset)?;
+        let fixed_start = offset + name_len;
+        let fixed = message
+            .get(fixed_start..fixed_start + 10)
+            .ok_or(DecodeError::Truncated {
+                offset: message.len(),
+            })?;
+        let answer_type = RecordType::from(u16::from_be_bytes([fixed[0], fixed[1]]));
+        let class = RecordClass::from(u16::from_be_bytes([fixed[2], fixed[3]]));
+        let ttl = u32::from_be_bytes([fixed[4], fixed[5], fixed[6], fixed[7]]);
+        let data_length = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+        let data = RData::from_bytes(answer_type, message, fixed_start + 10, data_length)?;
+        Ok((
+            Self {
+                domain_name: labels,
+                answer_type,
+                class,
+                ttl,
+                data,
+            },
+            name_len + 10 + data_length,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(text: &str) -> DomainName {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn test_rdata_to_from_bytes() {
+        let cases = [
+            (RecordType::A, RData::A(Ipv4Addr::new(93, 184, 216, 34))),
+            (
+                RecordType::Aaaa,
+                RData::Aaaa("2606:2800:220:1::1".parse().unwrap()),
+            ),
+            (RecordType::Ns, RData::Ns(name("a.iana-servers.net"))),
+            (RecordType::Cname, RData::Cname(name("example.com"))),
+            (RecordType::Ptr, RData::Ptr(name("host.example.com"))),
+            (
+                RecordType::Mx,
+                RData::Mx {
+                    preference: 10,
+                    exchange: name("mail.example.com"),
+                },
+            ),
+            (
+                RecordType::Txt,
+                RData::Txt(vec![b"v=spf1".to_vec(), b"-all".to_vec()]),
+            ),
+            (
+                RecordType::Soa,
+                RData::Soa {
+                    mname: name("ns.icann.org"),
+                    rname: name("noc.dns.icann.org"),
+                    serial: 2024081400,
+                    refresh: 7200,
+                    retry: 3600,
+                    expire: 1209600,
+                    minimum: 3600,
+                },
+            ),
+            (
+                RecordType::Srv,
+                RData::Srv {
+                    priority: 0,
+                    weight: 5,
+                    port: 5060,
+                    target: name("sip.example.com"),
+                },
+            ),
+            (
+                RecordType::Caa,
+                RData::Caa {
+                    flags: 0,
+                    tag: b"issue".to_vec(),
+                    value: b"letsencrypt.org".to_vec(),
+                },
+            ),
+            (RecordType::Unknown(99), RData::Unknown(vec![1, 2, 3])),
+        ];
+        for (record_type, rdata) in cases {
+            let bytes = rdata.to_bytes();
+            assert_eq!(
+                RData::from_bytes(record_type, &bytes, 0, bytes.len()),
+                Ok(rdata)
+            );
         }
-        bytes.push(0);
-        bytes.push((self.answer_type >> 8) as u8);
-        bytes.push(self.answer_type as u8);
-        bytes.push((self.class >> 8) as u8);
-        bytes.push(self.class as u8);
-        bytes.push((self.ttl >> 24) as u8);
-        bytes.push((self.ttl >> 16) as u8);
-        bytes.push((self.ttl >> 8) as u8);
-        bytes.push(self.ttl as u8);
-        bytes.push((self.data_length >> 8) as u8);
-        bytes.push(self.data_length as u8);
-        bytes.extend(self.data.iter());
-        bytes
-    }
-
-    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
-        let mut iter = buf.iter();
-        let mut labels = Vec::<Label>::new();
-        loop {
-            let length = *iter.next()?;
-            if length == 0x00 {
-                break;
+    }
+
+    #[test]
+    fn test_rdata_from_bytes_wire() {
+        assert_eq!(
+            RData::from_bytes(RecordType::Mx, &[0, 10, 4, 109, 97, 105, 108, 0], 0, 8),
+            Ok(RData::Mx {
+                preference: 10,
+                exchange: name("mail"),
+            })
+        );
+        let bad_a = Err(DecodeError::BadRData {
+            offset: 0,
+            record_type: RecordType::A,
+        });
+        assert_eq!(RData::from_bytes(RecordType::A, &[127, 0, 0], 0, 3), bad_a);
+        assert_eq!(
+            RData::from_bytes(RecordType::A, &[127, 0, 0, 1, 1], 0, 5),
+            bad_a
+        );
+        assert_eq!(
+            RData::from_bytes(RecordType::A, &[127, 0, 0, 1], 0, 5),
+            Err(DecodeError::Truncated { offset: 4 })
+        );
+        assert!(RData::from_bytes(RecordType::Txt, &[5, 104, 105], 0, 3).is_err());
+    }
+
+    #[test]
+    fn test_record_to_from_bytes() {
+        let record = ResourceRecord::new(
+            name("example.com"),
+            RecordType::A,
+            RecordClass::In,
+            300,
+            RData::A(Ipv4Addr::new(127, 0, 0, 1)),
+        );
+        let bytes = record.to_bytes();
+        assert_eq!(
+            bytes,
+            [
+                7, 101, 120, 97, 109, 112, 108, 101, 3, 99, 111, 109, 0, 0, 1, 0, 1, 0, 0, 1, 44,
+                0, 4, 127, 0, 0, 1
+            ]
+        );
+        let (decoded, len) = ResourceRecord::from_bytes(&bytes, 0).unwrap();
+        assert_eq!(len, bytes.len());
+        assert_eq!(decoded, record);
+    }
+
+    #[test]
+    fn test_record_from_bytes_compressed() {
+        // "example.com" at 0, "www" + pointer to it at 13, then an MX record owned by
+        // "mail" + pointer to "www" (a chain of two pointers) whose exchange is a bare pointer
+        let message = [
+            7, 101, 120, 97, 109, 112, 108, 101, 3, 99, 111, 109, 0, 3, 119, 119, 119, 0xC0, 0, 4,
+            109, 97, 105, 108, 0xC0, 13, 0, 15, 0, 1, 0, 0, 0, 60, 0, 4, 0, 10, 0xC0, 13,
+        ];
+        let (record, len) = ResourceRecord::from_bytes(&message, 19).unwrap();
+        assert_eq!(len, message.len() - 19);
+        assert_eq!(record.domain_name, name("mail.www.example.com"));
+        assert_eq!(
+            record.data,
+            RData::Mx {
+                preference: 10,
+                exchange: name("www.example.com"),
             }
-            let (content_len, content) = {
-                let content = iter
-                    .clone()
-                    .take(length as usize)
-                    .map(|&x| x as char)
-                    .collect();
-                iter.nth(length as usize - 1);
-                (length, content)
-            };
-            labels.push(Label {
-                length: content_len,
-                content,
-            });
-        }
-        let answer_type = u16::from_be_bytes([*iter.next()?, *iter.next()?]);
-        let class = u16::from_be_bytes([*iter.next()?, *iter.next()?]);
-        let ttl = u32::from_be_bytes([*iter.next()?, *iter.next()?, *iter.next()?, *iter.next()?]);
-        let data_length = u16::from_be_bytes([*iter.next()?, *iter.next()?]);
-        let data = iter.take(data_length as usize).copied().collect();
-        Some(Self {
-            domain_name: labels,
-            answer_type,
-            class,
-            ttl,
-            data_length,
-            data,
-        })
+        );
     }
 }
@@ -1,2 +1,5 @@
-mod dns_field_codes;
+pub mod cache;
 pub mod server;
+pub mod tcp;
+pub mod thread_pool;
+pub mod upstream;
@@ -0,0 +1,1120 @@
+use crate::dns_protocol::{
+    dns_field_codes::{RecordClass, RecordType, ResponseCode},
+    dns_header::DnsHeader,
+    dns_message::DnsMessage,
+    dns_name::DomainName,
+    dns_question::DnsQuestion,
+    dns_resource_record::{RData, ResourceRecord},
+};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+mod snapshot;
+
+/// CNAMEs followed when answering from the cache before giving up and asking upstream
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// How often lookups and inserts also sweep out every expired entry
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Bytes counted for each entry on top of its records, roughly what the key and bookkeeping
+/// take up
+const ENTRY_OVERHEAD: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CacheConfig {
+    /// Upper bound on how long anything is cached, whatever TTL the upstream gave it
+    pub max_ttl: u32,
+    /// Upper bound for NXDOMAIN and NODATA answers (RFC 2308 section 5 suggests 3 hours)
+    pub max_negative_ttl: u32,
+    /// Seconds an entry is kept after it expires, in case upstream can't be reached when it's
+    /// next needed (RFC 8767). Zero turns serve-stale off.
+    pub stale_window: u32,
+    /// TTL given to records served stale (RFC 8767 section 4 recommends 30 seconds)
+    pub stale_answer_ttl: u32,
+    /// How long a query with a stale answer to fall back on waits for upstream before getting
+    /// the stale answer, while the lookup carries on in the background (RFC 8767's client
+    /// response timer, which it suggests be 1.8 seconds)
+    pub client_response_timeout: Duration,
+    /// Seconds after a failed lookup during which a question with a stale answer gets it
+    /// straight away rather than asking upstream again (RFC 8767's failure recheck timer)
+    pub failure_recheck: u32,
+    /// A popular entry is refreshed from upstream once this fraction of its TTL or less remains.
+    /// Zero turns prefetching off.
+    pub prefetch_threshold: f64,
+    /// Hits an entry needs before it counts as popular
+    pub prefetch_min_hits: u32,
+    /// Upper bound on the size of everything cached, counting each entry's records in wire
+    /// format. The least recently used entries are evicted to stay under it.
+    pub max_bytes: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            max_ttl: 86400,
+            max_negative_ttl: 10800,
+            stale_window: 86400,
+            stale_answer_ttl: 30,
+            client_response_timeout: Duration::from_millis(1800),
+            failure_recheck: 30,
+            prefetch_threshold: 0.1,
+            prefetch_min_hits: 3,
+            max_bytes: 32 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub name: DomainName,
+    pub record_type: RecordType,
+    pub class: RecordClass,
+}
+
+#[derive(Debug, Clone)]
+enum CacheData {
+    Records(Vec<ResourceRecord>),
+    /// The name doesn't exist (NXDOMAIN) or has no records of the type (NODATA), along with the
+    /// SOA record from the authority section that has to go back out with the answer
+    Negative {
+        response_code: ResponseCode,
+        soa: ResourceRecord,
+    },
+}
+
+/// One RRset or negative answer, with the TTL it had when it was stored
+#[derive(Debug, Clone)]
+struct CacheEntry {
+    data: CacheData,
+    stored: Instant,
+    ttl: u32,
+    /// Times the entry has been used in an answer, carried over when it's replaced
+    hits: u32,
+    /// Set once a refresh has been asked for, so only one is
+    prefetching: bool,
+    /// Whether the upstream had validated the answer it came in (the AD bit)
+    authenticated: bool,
+    /// What the entry counts against `CacheConfig::max_bytes`
+    size: usize,
+    /// When the entry was last stored or used, on `CacheState`'s clock
+    last_used: u64,
+}
+
+/// How full the cache is and what it's had to drop to stay that way
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub bytes: usize,
+    /// Entries removed to make room before they were past their stale window
+    pub evictions: u64,
+}
+
+/// An answer from the cache
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheHit {
+    pub response: DnsMessage,
+    /// Set when part of the answer is popular and close to expiring, so the question should be
+    /// asked upstream again in the background to refresh it
+    pub prefetch: bool,
+}
+
+impl CacheEntry {
+    fn new(data: CacheData, stored: Instant, ttl: u32, hits: u32, authenticated: bool) -> Self {
+        Self {
+            size: entry_size(&data),
+            data,
+            stored,
+            ttl,
+            hits,
+            prefetching: false,
+            authenticated,
+            last_used: 0,
+        }
+    }
+
+    /// Seconds left to live at `now`, or `None` once expired
+    fn remaining_ttl(&self, now: Instant) -> Option<u32> {
+        let elapsed = now.saturating_duration_since(self.stored).as_secs();
+        (elapsed < self.ttl as u64).then(|| self.ttl - elapsed as u32)
+    }
+
+    /// Whether the entry has been expired for longer than `stale_window` seconds
+    fn is_past_stale_window(&self, now: Instant, stale_window: u32) -> bool {
+        let elapsed = now.saturating_duration_since(self.stored).as_secs();
+        elapsed >= self.ttl as u64 + stale_window as u64
+    }
+}
+
+/// Copies of `records` with their TTLs counted down to `ttl`
+fn with_ttl(records: &[ResourceRecord], ttl: u32) -> impl Iterator<Item = ResourceRecord> + '_ {
+    records.iter().map(move |record| ResourceRecord {
+        ttl,
+        ..record.clone()
+    })
+}
+
+/// Approximate bytes `data` takes up: its records in wire format plus a fixed allowance
+fn entry_size(data: &CacheData) -> usize {
+    let records = match data {
+        CacheData::Records(records) => records.iter().map(|record| record.to_bytes().len()).sum(),
+        CacheData::Negative { soa, .. } => soa.to_bytes().len(),
+    };
+    records + ENTRY_OVERHEAD
+}
+
+struct CacheState {
+    entries: HashMap<CacheKey, CacheEntry>,
+    /// Keys by `CacheEntry::last_used`, least recently used first
+    recency: BTreeMap<u64, CacheKey>,
+    /// Ticks on every store and use, so `last_used` values are unique
+    clock: u64,
+    bytes: usize,
+    max_bytes: usize,
+    evictions: u64,
+    /// When lookups for these keys last failed, kept for `failure_recheck` seconds
+    failures: HashMap<CacheKey, Instant>,
+    /// Keys with a lookup under way on behalf of a client that's been served a stale answer
+    refreshing: HashSet<CacheKey>,
+    next_sweep: Instant,
+    stale_window: u32,
+    failure_recheck: u32,
+}
+
+/// Answers from the upstream resolver, kept as RRsets keyed by owner name, type and class, so a
+/// repeated question can be answered without asking upstream again. Names and types that don't
+/// exist are remembered too (RFC 2308).
+pub struct Cache {
+    config: CacheConfig,
+    state: Mutex<CacheState>,
+    /// Held for the whole of a save, so saves from different threads can't interleave
+    save_lock: Mutex<()>,
+}
+
+impl Cache {
+    pub fn new(config: CacheConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(CacheState {
+                entries: HashMap::new(),
+                recency: BTreeMap::new(),
+                clock: 0,
+                bytes: 0,
+                max_bytes: config.max_bytes,
+                evictions: 0,
+                failures: HashMap::new(),
+                refreshing: HashSet::new(),
+                next_sweep: Instant::now() + SWEEP_INTERVAL,
+                stale_window: config.stale_window,
+                failure_recheck: config.failure_recheck,
+            }),
+            save_lock: Mutex::new(()),
+        }
+    }
+
+    pub fn config(&self) -> &CacheConfig {
+        &self.config
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let state = self.state.lock().unwrap();
+        CacheStats {
+            entries: state.entries.len(),
+            bytes: state.bytes,
+            evictions: state.evictions,
+        }
+    }
+
+    /// A response to `question` built from cached RRsets, following any CNAMEs, or `None` if
+    /// some part of the answer is missing or has expired
+    pub fn get(&self, question: &DnsQuestion) -> Option<CacheHit> {
+        self.get_at(question, Instant::now(), false)
+    }
+
+    /// Like `get`, but also uses entries that have expired within the stale window, giving
+    /// their records the stale answer TTL. Only for when upstream can't provide a fresh answer.
+    pub fn get_stale(&self, question: &DnsQuestion) -> Option<CacheHit> {
+        self.get_at(question, Instant::now(), true)
+    }
+
+    /// Notes that upstream couldn't answer `question`, so for the next `failure_recheck` seconds
+    /// `failed_recently` says not to bother asking it again
+    pub fn record_failure(&self, question: &DnsQuestion) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .failures
+            .insert(question_key(question), Instant::now());
+    }
+
+    /// Claims the background refresh of `question`'s stale answer. Returns false if one is
+    /// already under way, in which case there's no point starting another.
+    pub fn start_refresh(&self, question: &DnsQuestion) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.refreshing.insert(question_key(question))
+    }
+
+    /// Ends the refresh claimed by `start_refresh`, whether or not it succeeded
+    pub fn finish_refresh(&self, question: &DnsQuestion) {
+        let mut state = self.state.lock().unwrap();
+        state.refreshing.remove(&question_key(question));
+    }
+
+    /// Releases the prefetch claimed by a hit on `question`'s answer, for when it couldn't be
+    /// queued or the lookup failed, so a later hit can try again before the answer expires
+    pub fn prefetch_failed(&self, question: &DnsQuestion) {
+        let mut state = self.state.lock().unwrap();
+        let mut name = question.domain_name.clone();
+        for _ in 0..MAX_CNAME_CHAIN {
+            let key = CacheKey {
+                name: name.clone(),
+                record_type: question.question_type,
+                class: question.class,
+            };
+            if let Some(entry) = state.entries.get_mut(&key) {
+                entry.prefetching = false;
+                return;
+            }
+            let cname_key = CacheKey {
+                record_type: RecordType::Cname,
+                ..key
+            };
+            let Some(entry) = state.entries.get_mut(&cname_key) else {
+                return;
+            };
+            entry.prefetching = false;
+            name = match &entry.data {
+                CacheData::Records(records) => match records.first().map(|record| &record.data) {
+                    Some(RData::Cname(target)) => target.clone(),
+                    _ => return,
+                },
+                CacheData::Negative { .. } => return,
+            };
+        }
+    }
+
+    /// Whether a lookup for `question` failed within the last `failure_recheck` seconds
+    pub fn failed_recently(&self, question: &DnsQuestion) -> bool {
+        self.failed_recently_at(question, Instant::now())
+    }
+
+    /// Stores the RRsets in `response` that answer `question`, or the fact that there aren't
+    /// any. Records that don't belong to the question's name or the CNAME chain leading from it
+    /// are ignored, so an upstream can't slip unrelated data into the cache. Whether the upstream
+    /// validated the answer is kept too, so a hit only claims AD if every part of it was.
+    pub fn insert(&self, question: &DnsQuestion, response: &DnsMessage) {
+        self.insert_at(question, response, Instant::now())
+    }
+
+    fn failed_recently_at(&self, question: &DnsQuestion, now: Instant) -> bool {
+        let state = self.state.lock().unwrap();
+        state
+            .failures
+            .get(&question_key(question))
+            .is_some_and(|&failed| {
+                now.saturating_duration_since(failed).as_secs() < state.failure_recheck as u64
+            })
+    }
+
+    fn get_at(&self, question: &DnsQuestion, now: Instant, stale: bool) -> Option<CacheHit> {
+        let stale_ttl = stale.then_some(self.config.stale_answer_ttl);
+        if question.question_type == RecordType::Any {
+            return None;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.sweep_if_due(now);
+        let mut response = cached_response(question);
+        let mut prefetch = false;
+        let mut authenticated = true;
+        let mut name = question.domain_name.clone();
+        for _ in 0..MAX_CNAME_CHAIN {
+            let key = CacheKey {
+                name: name.clone(),
+                record_type: question.question_type,
+                class: question.class,
+            };
+            if let Some((entry, ttl)) = state.get_usable(&key, now, stale_ttl) {
+                prefetch |= self.record_hit(entry, now);
+                authenticated &= entry.authenticated;
+                match &entry.data {
+                    CacheData::Records(records) => response.answers.extend(with_ttl(records, ttl)),
+                    CacheData::Negative { response_code, soa } => {
+                        response.header.response_code = *response_code;
+                        response
+                            .authorities
+                            .extend(with_ttl(std::slice::from_ref(soa), ttl));
+                    }
+                }
+                response.header.set_authentic_data(authenticated);
+                response.restore_case(&question.domain_name);
+                return Some(CacheHit { response, prefetch });
+            }
+            if question.question_type == RecordType::Cname {
+                return None;
+            }
+            let cname_key = CacheKey {
+                record_type: RecordType::Cname,
+                ..key
+            };
+            let (entry, ttl) = state.get_usable(&cname_key, now, stale_ttl)?;
+            prefetch |= self.record_hit(entry, now);
+            authenticated &= entry.authenticated;
+            let CacheData::Records(records) = &entry.data else {
+                return None;
+            };
+            name = match records.first().map(|record| &record.data) {
+                Some(RData::Cname(target)) => target.clone(),
+                _ => return None,
+            };
+            response.answers.extend(with_ttl(records, ttl));
+        }
+        None
+    }
+
+    /// Counts a hit on `entry` and says whether it's now due a prefetch
+    fn record_hit(&self, entry: &mut CacheEntry, now: Instant) -> bool {
+        entry.hits = entry.hits.saturating_add(1);
+        let threshold = self.config.prefetch_threshold;
+        let due = threshold > 0.0
+            && !entry.prefetching
+            && entry.hits >= self.config.prefetch_min_hits
+            && entry
+                .remaining_ttl(now)
+                .is_some_and(|ttl| ttl as f64 <= entry.ttl as f64 * threshold);
+        entry.prefetching |= due;
+        due
+    }
+
+    fn insert_at(&self, question: &DnsQuestion, response: &DnsMessage, now: Instant) {
+        let response_code = response.header.response_code;
+        if !matches!(
+            response_code,
+            ResponseCode::NoError | ResponseCode::NameError
+        ) || question.question_type == RecordType::Any
+        {
+            return;
+        }
+        let mut chain = HashSet::from([question.domain_name.clone()]);
+        let mut rrsets = HashMap::<CacheKey, Vec<ResourceRecord>>::new();
+        for record in &response.answers {
+            if record.class != question.class || !chain.contains(&record.domain_name) {
+                continue;
+            }
+            if let RData::Cname(target) = &record.data {
+                chain.insert(target.clone());
+            }
+            let key = CacheKey {
+                name: record.domain_name.clone(),
+                record_type: record.answer_type,
+                class: record.class,
+            };
+            rrsets.entry(key).or_default().push(record.clone());
+        }
+        let negative = self.negative_entry(question, response, &rrsets);
+        let authenticated = response.header.authentic_data();
+
+        let mut state = self.state.lock().unwrap();
+        state.sweep_if_due(now);
+        state.failures.remove(&question_key(question));
+        for (key, records) in rrsets {
+            // An RRset's records should share a TTL (RFC 2181 section 5.2); if not, the lowest wins
+            let ttl = records
+                .iter()
+                .map(|record| record.ttl)
+                .min()
+                .unwrap_or(0)
+                .min(self.config.max_ttl);
+            state.store(key, CacheData::Records(records), ttl, authenticated, now);
+        }
+        if let Some((key, data, ttl)) = negative {
+            state.store(key, data, ttl, authenticated, now);
+        }
+    }
+
+    /// If the response says the name at the end of the CNAME chain has no records of the
+    /// question's type, the entry recording that. Its TTL is the lower of the SOA's own TTL and
+    /// its MINIMUM field (RFC 2308 section 5); without an SOA it can't be cached at all.
+    fn negative_entry(
+        &self,
+        question: &DnsQuestion,
+        response: &DnsMessage,
+        rrsets: &HashMap<CacheKey, Vec<ResourceRecord>>,
+    ) -> Option<(CacheKey, CacheData, u32)> {
+        let mut key = CacheKey {
+            name: question.domain_name.clone(),
+            record_type: question.question_type,
+            class: question.class,
+        };
+        for _ in 0..MAX_CNAME_CHAIN {
+            if rrsets.contains_key(&key) {
+                return None;
+            }
+            let cname_key = CacheKey {
+                record_type: RecordType::Cname,
+                ..key.clone()
+            };
+            match rrsets.get(&cname_key).and_then(|records| records.first()) {
+                Some(ResourceRecord {
+                    data: RData::Cname(target),
+                    ..
+                }) if question.question_type != RecordType::Cname => key.name = target.clone(),
+                _ => break,
+            }
+        }
+        let soa = response.authorities.iter().find(|record| {
+            record.answer_type == RecordType::Soa
+                && record.class == question.class
+                && key.name.is_subdomain_of(&record.domain_name)
+        })?;
+        let RData::Soa { minimum, .. } = soa.data else {
+            return None;
+        };
+        let ttl = soa.ttl.min(minimum).min(self.config.max_negative_ttl);
+        let data = CacheData::Negative {
+            response_code: response.header.response_code,
+            soa: soa.clone(),
+        };
+        Some((key, data, ttl))
+    }
+}
+
+impl CacheState {
+    /// The entry for `key` and the TTL to serve it with: what remains of its own TTL, or
+    /// `stale_ttl` if it has expired but is still within the stale window. The entry becomes
+    /// the most recently used; entries past the stale window are removed instead.
+    fn get_usable(
+        &mut self,
+        key: &CacheKey,
+        now: Instant,
+        stale_ttl: Option<u32>,
+    ) -> Option<(&mut CacheEntry, u32)> {
+        let entry = self.entries.get(key)?;
+        let ttl = match (entry.remaining_ttl(now), stale_ttl) {
+            (Some(ttl), _) => ttl,
+            _ if entry.is_past_stale_window(now, self.stale_window) => {
+                self.remove(key);
+                return None;
+            }
+            (None, Some(stale_ttl)) => stale_ttl,
+            (None, None) => return None,
+        };
+        let last_used = self.tick();
+        let entry = self.entries.get_mut(key)?;
+        self.recency.remove(&entry.last_used);
+        self.recency.insert(last_used, key.clone());
+        entry.last_used = last_used;
+        Some((entry, ttl))
+    }
+
+    fn store(
+        &mut self,
+        key: CacheKey,
+        data: CacheData,
+        ttl: u32,
+        authenticated: bool,
+        now: Instant,
+    ) {
+        if ttl == 0 {
+            return;
+        }
+        let hits = self.entries.get(&key).map_or(0, |entry| entry.hits);
+        self.add(key, CacheEntry::new(data, now, ttl, hits, authenticated));
+    }
+
+    /// Adds an entry as the most recently used, replacing any for the same key, and evicts the
+    /// least recently used ones until it fits. An entry bigger than the whole cache isn't added.
+    fn add(&mut self, key: CacheKey, mut entry: CacheEntry) {
+        self.remove(&key);
+        let size = entry.size;
+        if size > self.max_bytes {
+            return;
+        }
+        while self.bytes > self.max_bytes - size {
+            let Some((_, oldest)) = self.recency.pop_first() else {
+                break;
+            };
+            self.remove(&oldest);
+            self.evictions += 1;
+        }
+        entry.last_used = self.tick();
+        self.recency.insert(entry.last_used, key.clone());
+        self.bytes += size;
+        self.entries.insert(key, entry);
+    }
+
+    fn remove(&mut self, key: &CacheKey) -> Option<CacheEntry> {
+        let entry = self.entries.remove(key)?;
+        self.recency.remove(&entry.last_used);
+        self.bytes -= entry.size;
+        Some(entry)
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn sweep_if_due(&mut self, now: Instant) {
+        if now < self.next_sweep {
+            return;
+        }
+        let expired: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.is_past_stale_window(now, self.stale_window))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            self.remove(&key);
+        }
+        let failure_recheck = self.failure_recheck as u64;
+        self.failures
+            .retain(|_, failed| now.saturating_duration_since(*failed).as_secs() < failure_recheck);
+        self.next_sweep = now + SWEEP_INTERVAL;
+    }
+}
+
+fn question_key(question: &DnsQuestion) -> CacheKey {
+    CacheKey {
+        name: question.domain_name.clone(),
+        record_type: question.question_type,
+        class: question.class,
+    }
+}
+
+fn cached_response(question: &DnsQuestion) -> DnsMessage {
+    DnsMessage {
+        header: DnsHeader {
+            query_response_indicator: true,
+            recursion_available: true,
+            ..Default::default()
+        },
+        questions: vec![question.clone()],
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    pub(super) fn question(name: &str, question_type: RecordType) -> DnsQuestion {
+        DnsQuestion {
+            domain_name: name.parse().unwrap(),
+            question_type,
+            class: RecordClass::In,
+        }
+    }
+
+    pub(super) fn a_record(name: &str, ttl: u32, last_octet: u8) -> ResourceRecord {
+        ResourceRecord::new(
+            name.parse().unwrap(),
+            RecordType::A,
+            RecordClass::In,
+            ttl,
+            RData::A(Ipv4Addr::new(192, 0, 2, last_octet)),
+        )
+    }
+
+    fn cname_record(name: &str, ttl: u32, target: &str) -> ResourceRecord {
+        ResourceRecord::new(
+            name.parse().unwrap(),
+            RecordType::Cname,
+            RecordClass::In,
+            ttl,
+            RData::Cname(target.parse().unwrap()),
+        )
+    }
+
+    pub(super) fn response(answers: Vec<ResourceRecord>) -> DnsMessage {
+        DnsMessage {
+            answers,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_hit_counts_down_ttl() {
+        let cache = Cache::new(CacheConfig {
+            stale_window: 0,
+            ..Default::default()
+        });
+        let now = Instant::now();
+        let www = question("www.example.com", RecordType::A);
+        assert_eq!(cache.get_at(&www, now, false), None);
+
+        let answers = vec![
+            a_record("www.example.com", 300, 1),
+            a_record("www.example.com", 300, 2),
+        ];
+        cache.insert_at(&www, &response(answers.clone()), now);
+        assert_eq!(cache.len(), 1);
+
+        let hit = cache
+            .get_at(
+                &question("WWW.example.COM", RecordType::A),
+                now + Duration::from_secs(100),
+                false,
+            )
+            .unwrap()
+            .response;
+        assert_eq!(hit.header.response_code, ResponseCode::NoError);
+        assert_eq!(hit.answers.len(), 2);
+        assert_eq!(hit.answers[1].data, answers[1].data);
+        assert!(hit.answers.iter().all(|record| record.ttl == 200));
+        assert_eq!(hit.answers[0].domain_name.to_string(), "WWW.example.COM.");
+
+        assert_eq!(
+            cache.get_at(&question("www.example.com", RecordType::Aaaa), now, false),
+            None
+        );
+        assert_eq!(
+            cache.get_at(&www, now + Duration::from_secs(300), false),
+            None
+        );
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_follows_cname_chain() {
+        let cache = Cache::new(CacheConfig::default());
+        let now = Instant::now();
+        let www = question("www.example.com", RecordType::A);
+        let answers = vec![
+            cname_record("www.example.com", 600, "cdn.example.net"),
+            a_record("cdn.example.net", 60, 1),
+            // Not reachable from the question, so not cached
+            a_record("evil.example.org", 600, 66),
+        ];
+        cache.insert_at(&www, &response(answers.clone()), now);
+        assert_eq!(cache.len(), 2);
+
+        let hit = cache
+            .get_at(&www, now + Duration::from_secs(10), false)
+            .unwrap()
+            .response;
+        assert_eq!(hit.answers.len(), 2);
+        assert_eq!(hit.answers[0].data, answers[0].data);
+        assert_eq!(hit.answers[0].ttl, 590);
+        assert_eq!(hit.answers[1].ttl, 50);
+        assert_eq!(
+            cache.get_at(&question("evil.example.org", RecordType::A), now, false),
+            None
+        );
+
+        // The CNAME alone still answers a CNAME question once the A record has expired
+        let later = now + Duration::from_secs(60);
+        assert_eq!(cache.get_at(&www, later, false), None);
+        let hit = cache
+            .get_at(
+                &question("www.example.com", RecordType::Cname),
+                later,
+                false,
+            )
+            .unwrap()
+            .response;
+        assert_eq!(hit.answers.len(), 1);
+    }
+
+    pub(super) fn soa_record(name: &str, ttl: u32, minimum: u32) -> ResourceRecord {
+        ResourceRecord::new(
+            name.parse().unwrap(),
+            RecordType::Soa,
+            RecordClass::In,
+            ttl,
+            RData::Soa {
+                mname: "ns.example.com".parse().unwrap(),
+                rname: "hostmaster.example.com".parse().unwrap(),
+                serial: 1,
+                refresh: 7200,
+                retry: 3600,
+                expire: 1209600,
+                minimum,
+            },
+        )
+    }
+
+    pub(super) fn negative_response(
+        response_code: ResponseCode,
+        answers: Vec<ResourceRecord>,
+        soa: ResourceRecord,
+    ) -> DnsMessage {
+        DnsMessage {
+            header: DnsHeader {
+                response_code,
+                ..Default::default()
+            },
+            answers,
+            authorities: vec![soa],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_nxdomain_cached_for_soa_minimum() {
+        let cache = Cache::new(CacheConfig::default());
+        let now = Instant::now();
+        let missing = question("missing.example.com", RecordType::A);
+        let soa = soa_record("example.com", 3600, 300);
+        let nxdomain = negative_response(ResponseCode::NameError, vec![], soa.clone());
+        cache.insert_at(&missing, &nxdomain, now);
+
+        let hit = cache
+            .get_at(&missing, now + Duration::from_secs(100), false)
+            .unwrap()
+            .response;
+        assert_eq!(hit.header.response_code, ResponseCode::NameError);
+        assert!(hit.answers.is_empty());
+        assert_eq!(hit.authorities.len(), 1);
+        assert_eq!(hit.authorities[0].data, soa.data);
+        assert_eq!(hit.authorities[0].ttl, 200);
+        assert_eq!(
+            cache.get_at(&missing, now + Duration::from_secs(300), false),
+            None
+        );
+
+        // The SOA's own TTL caps the negative TTL when it's lower than MINIMUM
+        let nxdomain = negative_response(
+            ResponseCode::NameError,
+            vec![],
+            soa_record("example.com", 30, 300),
+        );
+        cache.insert_at(&missing, &nxdomain, now);
+        assert_eq!(
+            cache
+                .get_at(&missing, now, false)
+                .unwrap()
+                .response
+                .authorities[0]
+                .ttl,
+            30
+        );
+    }
+
+    #[test]
+    fn test_nodata_after_cname_cached() {
+        let cache = Cache::new(CacheConfig::default());
+        let now = Instant::now();
+        let www = question("www.example.com", RecordType::Aaaa);
+        let nodata = negative_response(
+            ResponseCode::NoError,
+            vec![cname_record("www.example.com", 600, "host.example.com")],
+            soa_record("example.com", 3600, 60),
+        );
+        cache.insert_at(&www, &nodata, now);
+        assert_eq!(cache.len(), 2);
+
+        let hit = cache.get_at(&www, now, false).unwrap().response;
+        assert_eq!(hit.header.response_code, ResponseCode::NoError);
+        assert_eq!(hit.answers.len(), 1);
+        assert_eq!(hit.authorities[0].ttl, 60);
+        let hit = cache
+            .get_at(&question("host.example.com", RecordType::Aaaa), now, false)
+            .unwrap()
+            .response;
+        assert!(hit.answers.is_empty());
+        assert_eq!(hit.authorities.len(), 1);
+        assert_eq!(
+            cache.get_at(&question("host.example.com", RecordType::A), now, false),
+            None
+        );
+    }
+
+    #[test]
+    fn test_negative_answer_needs_relevant_soa() {
+        let cache = Cache::new(CacheConfig {
+            max_negative_ttl: 10,
+            ..Default::default()
+        });
+        let now = Instant::now();
+        let missing = question("missing.example.com", RecordType::A);
+        cache.insert_at(&missing, &response(vec![]), now);
+        let unrelated_soa = negative_response(
+            ResponseCode::NameError,
+            vec![],
+            soa_record("example.org", 3600, 300),
+        );
+        cache.insert_at(&missing, &unrelated_soa, now);
+        assert!(cache.is_empty());
+
+        let nxdomain = negative_response(
+            ResponseCode::NameError,
+            vec![],
+            soa_record("com", 3600, 300),
+        );
+        cache.insert_at(&missing, &nxdomain, now);
+        assert_eq!(
+            cache
+                .get_at(&missing, now, false)
+                .unwrap()
+                .response
+                .authorities[0]
+                .ttl,
+            10
+        );
+    }
+
+    #[test]
+    fn test_insert_skips_uncacheable() {
+        let cache = Cache::new(CacheConfig {
+            max_ttl: 100,
+            ..Default::default()
+        });
+        let now = Instant::now();
+        let www = question("www.example.com", RecordType::A);
+        cache.insert_at(
+            &www,
+            &response(vec![a_record("www.example.com", 0, 1)]),
+            now,
+        );
+        let mut servfail = response(vec![a_record("www.example.com", 60, 1)]);
+        servfail.header.response_code = ResponseCode::ServerFailure;
+        cache.insert_at(&www, &servfail, now);
+        assert!(cache.is_empty());
+
+        cache.insert_at(
+            &www,
+            &response(vec![a_record("www.example.com", 9999, 1)]),
+            now,
+        );
+        assert_eq!(
+            cache.get_at(&www, now, false).unwrap().response.answers[0].ttl,
+            100
+        );
+    }
+
+    #[test]
+    fn test_authenticated_only_if_every_part_was() {
+        let cache = Cache::new(CacheConfig::default());
+        let now = Instant::now();
+        let mut authenticated = response(vec![
+            cname_record("www.example.com", 600, "cdn.example.net"),
+            a_record("cdn.example.net", 600, 1),
+        ]);
+        authenticated.header.set_authentic_data(true);
+        let www = question("www.example.com", RecordType::A);
+        cache.insert_at(&www, &authenticated, now);
+        assert!(cache
+            .get_at(&www, now, false)
+            .unwrap()
+            .response
+            .header
+            .authentic_data());
+
+        // The target refreshed by an upstream that didn't validate it
+        let cdn = question("cdn.example.net", RecordType::A);
+        cache.insert_at(
+            &cdn,
+            &response(vec![a_record("cdn.example.net", 600, 1)]),
+            now,
+        );
+        assert!(!cache
+            .get_at(&cdn, now, false)
+            .unwrap()
+            .response
+            .header
+            .authentic_data());
+        assert!(!cache
+            .get_at(&www, now, false)
+            .unwrap()
+            .response
+            .header
+            .authentic_data());
+    }
+
+    #[test]
+    fn test_serve_stale() {
+        let cache = Cache::new(CacheConfig {
+            stale_window: 600,
+            stale_answer_ttl: 30,
+            ..Default::default()
+        });
+        let now = Instant::now();
+        let www = question("www.example.com", RecordType::A);
+        let answers = vec![
+            cname_record("www.example.com", 300, "host.example.com"),
+            a_record("host.example.com", 60, 1),
+        ];
+        cache.insert_at(&www, &response(answers), now);
+
+        // Fresh entries keep their own TTLs even when stale ones are allowed
+        let hit = cache
+            .get_at(&www, now + Duration::from_secs(10), true)
+            .unwrap()
+            .response;
+        assert_eq!(hit.answers[0].ttl, 290);
+        assert_eq!(hit.answers[1].ttl, 50);
+
+        let expired = now + Duration::from_secs(100);
+        assert_eq!(cache.get_at(&www, expired, false), None);
+        let hit = cache.get_at(&www, expired, true).unwrap().response;
+        assert_eq!(hit.answers.len(), 2);
+        assert_eq!(hit.answers[0].ttl, 200);
+        assert_eq!(hit.answers[1].ttl, 30);
+
+        let past_window = now + Duration::from_secs(660);
+        assert_eq!(cache.get_at(&www, past_window, true), None);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_failure_remembered_until_recheck() {
+        let cache = Cache::new(CacheConfig {
+            failure_recheck: 30,
+            ..Default::default()
+        });
+        let www = question("www.example.com", RecordType::A);
+        cache.record_failure(&www);
+        let now = Instant::now();
+        assert!(cache.failed_recently_at(&www, now));
+        assert!(!cache.failed_recently_at(&question("www.example.com", RecordType::Aaaa), now));
+        assert!(!cache.failed_recently_at(&www, now + Duration::from_secs(31)));
+
+        // An answer arriving clears it
+        cache.insert(&www, &response(vec![a_record("www.example.com", 600, 1)]));
+        assert!(!cache.failed_recently(&www));
+    }
+
+    #[test]
+    fn test_one_refresh_at_a_time() {
+        let cache = Cache::new(CacheConfig::default());
+        let www = question("www.example.com", RecordType::A);
+        assert!(cache.start_refresh(&www));
+        assert!(!cache.start_refresh(&www));
+        assert!(cache.start_refresh(&question("www.example.com", RecordType::Aaaa)));
+        cache.finish_refresh(&www);
+        assert!(cache.start_refresh(&www));
+    }
+
+    #[test]
+    fn test_prefetch_popular_entries() {
+        let cache = Cache::new(CacheConfig {
+            prefetch_threshold: 0.1,
+            prefetch_min_hits: 3,
+            ..Default::default()
+        });
+        let now = Instant::now();
+        let www = question("www.example.com", RecordType::A);
+        let answer = response(vec![a_record("www.example.com", 100, 1)]);
+        cache.insert_at(&www, &answer, now);
+
+        // Popular but not near expiry, then near expiry but not popular enough
+        assert!(!cache.get_at(&www, now, false).unwrap().prefetch);
+        assert!(!cache.get_at(&www, now, false).unwrap().prefetch);
+        let near_expiry = now + Duration::from_secs(91);
+        let other = question("other.example.com", RecordType::A);
+        cache.insert_at(
+            &other,
+            &response(vec![a_record("other.example.com", 100, 1)]),
+            now,
+        );
+        assert!(!cache.get_at(&other, near_expiry, false).unwrap().prefetch);
+
+        // Only the first hit that qualifies asks for a refresh
+        assert!(cache.get_at(&www, near_expiry, false).unwrap().prefetch);
+        assert!(!cache.get_at(&www, near_expiry, false).unwrap().prefetch);
+
+        // A prefetch that failed leaves the next hit free to ask again
+        cache.prefetch_failed(&www);
+        assert!(cache.get_at(&www, near_expiry, false).unwrap().prefetch);
+        assert!(!cache.get_at(&www, near_expiry, false).unwrap().prefetch);
+
+        // The refreshed entry keeps its hit count
+        cache.insert_at(&www, &answer, near_expiry);
+        let later = near_expiry + Duration::from_secs(95);
+        assert!(cache.get_at(&www, later, false).unwrap().prefetch);
+
+        // Stale answers are never prefetched
+        let cache = Cache::new(CacheConfig {
+            prefetch_min_hits: 0,
+            ..Default::default()
+        });
+        cache.insert_at(&www, &answer, now);
+        let expired = now + Duration::from_secs(200);
+        assert!(!cache.get_at(&www, expired, true).unwrap().prefetch);
+    }
+
+    #[test]
+    fn test_sweep_evicts_expired_entries() {
+        let cache = Cache::new(CacheConfig {
+            stale_window: 30,
+            ..Default::default()
+        });
+        let now = Instant::now();
+        let short = question("short.example.com", RecordType::A);
+        let long = question("long.example.com", RecordType::A);
+        cache.insert_at(
+            &short,
+            &response(vec![a_record("short.example.com", 10, 1)]),
+            now,
+        );
+        cache.insert_at(
+            &long,
+            &response(vec![a_record("long.example.com", 3600, 1)]),
+            now,
+        );
+
+        let unrelated = question("other.example.com", RecordType::A);
+        cache.get_at(&unrelated, now + SWEEP_INTERVAL, false);
+        assert_eq!(cache.len(), 1);
+        assert!(cache.get_at(&long, now + SWEEP_INTERVAL, false).is_some());
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let entry_bytes = entry_size(&CacheData::Records(vec![a_record("a.example.com", 300, 1)]));
+        let cache = Cache::new(CacheConfig {
+            max_bytes: entry_bytes * 2,
+            ..Default::default()
+        });
+        let now = Instant::now();
+        let a = question("a.example.com", RecordType::A);
+        let b = question("b.example.com", RecordType::A);
+        let c = question("c.example.com", RecordType::A);
+        cache.insert_at(&a, &response(vec![a_record("a.example.com", 300, 1)]), now);
+        cache.insert_at(&b, &response(vec![a_record("b.example.com", 300, 1)]), now);
+        assert!(cache.get_at(&a, now, false).is_some());
+
+        cache.insert_at(&c, &response(vec![a_record("c.example.com", 300, 1)]), now);
+        assert!(cache.get_at(&a, now, false).is_some());
+        assert_eq!(cache.get_at(&b, now, false), None);
+        assert!(cache.get_at(&c, now, false).is_some());
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                entries: 2,
+                bytes: entry_bytes * 2,
+                evictions: 1,
+            }
+        );
+
+        // Replacing an entry frees its old size rather than evicting anything
+        cache.insert_at(&c, &response(vec![a_record("c.example.com", 300, 2)]), now);
+        assert_eq!(cache.stats().evictions, 1);
+        assert_eq!(cache.stats().bytes, entry_bytes * 2);
+    }
+
+    #[test]
+    fn test_entry_bigger_than_cache_not_stored() {
+        let cache = Cache::new(CacheConfig {
+            max_bytes: 200,
+            ..Default::default()
+        });
+        let www = question("www.example.com", RecordType::A);
+        let records = (1..=10)
+            .map(|last_octet| a_record("www.example.com", 300, last_octet))
+            .collect();
+        cache.insert(&www, &response(records));
+        assert!(cache.is_empty());
+        assert_eq!(cache.stats(), CacheStats::default());
+    }
+}
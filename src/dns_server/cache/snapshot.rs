@@ -0,0 +1,270 @@
+use super::{Cache, CacheData, CacheEntry, CacheKey};
+use crate::dns_protocol::{
+    dns_encoder::DnsEncoder, dns_field_codes::ResponseCode, dns_name::decode_name,
+    dns_resource_record::ResourceRecord,
+};
+use rkyv::{rancor, util::AlignedVec, Archive, Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Bumped whenever the layout changes, so an old file is rejected rather than misread
+const SNAPSHOT_VERSION: u32 = 2;
+
+#[derive(Archive, Serialize, Deserialize)]
+struct Snapshot {
+    version: u32,
+    /// Seconds since the Unix epoch when the snapshot was taken
+    saved_at: u64,
+    entries: Vec<SnapshotEntry>,
+}
+
+/// A cache entry with its names and records in uncompressed wire format, so the protocol types
+/// don't need to know about rkyv
+#[derive(Archive, Serialize, Deserialize)]
+struct SnapshotEntry {
+    name: Vec<u8>,
+    record_type: u16,
+    class: u16,
+    /// The RRset, or for a negative entry just its SOA record
+    records: Vec<Vec<u8>>,
+    /// Set for NXDOMAIN and NODATA entries
+    negative_response_code: Option<u16>,
+    ttl: u32,
+    /// Seconds since the entry was stored, as of `Snapshot::saved_at`
+    age: u64,
+    hits: u32,
+    authenticated: bool,
+}
+
+impl Cache {
+    /// Writes every entry that's still within its stale window to `path` and returns how many
+    /// there were. The file is replaced in one step, so a crash mid-save can't corrupt it, and
+    /// only one save runs at a time, so two can't write the temporary file at once.
+    pub fn save(&self, path: &Path) -> Result<usize, anyhow::Error> {
+        let _saving = self.save_lock.lock().unwrap();
+        let snapshot = self.snapshot(Instant::now(), unix_time());
+        let bytes = rkyv::to_bytes::<rancor::Error>(&snapshot)?;
+        let temp_path = path.with_extension("tmp");
+        fs::write(&temp_path, &bytes)?;
+        fs::rename(&temp_path, path)?;
+        Ok(snapshot.entries.len())
+    }
+
+    /// Adds the entries saved in `path`, aged by however long ago it was written, and returns
+    /// how many were still worth keeping
+    pub fn load(&self, path: &Path) -> Result<usize, anyhow::Error> {
+        let bytes = fs::read(path)?;
+        let mut aligned = AlignedVec::<16>::with_capacity(bytes.len());
+        aligned.extend_from_slice(&bytes);
+        let snapshot = rkyv::from_bytes::<Snapshot, rancor::Error>(&aligned)?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(anyhow::anyhow!(
+                "cache snapshot has version {}, expected {}",
+                snapshot.version,
+                SNAPSHOT_VERSION
+            ));
+        }
+        Ok(self.restore(snapshot, Instant::now(), unix_time()))
+    }
+
+    fn snapshot(&self, now: Instant, now_unix: u64) -> Snapshot {
+        let state = self.state.lock().unwrap();
+        // Least recently used first, so restoring them in order keeps their recency
+        let entries = state
+            .recency
+            .values()
+            .map(|key| (key, &state.entries[key]))
+            .filter(|(_, entry)| !entry.is_past_stale_window(now, state.stale_window))
+            .map(|(key, entry)| {
+                let mut name = DnsEncoder::uncompressed();
+                name.write_name(&key.name);
+                let (records, negative_response_code) = match &entry.data {
+                    CacheData::Records(records) => (records.iter().collect::<Vec<_>>(), None),
+                    CacheData::Negative { response_code, soa } => {
+                        (vec![soa], Some(u16::from(*response_code)))
+                    }
+                };
+                SnapshotEntry {
+                    name: name.into_bytes(),
+                    record_type: key.record_type.into(),
+                    class: key.class.into(),
+                    records: records.iter().map(|record| record.to_bytes()).collect(),
+                    negative_response_code,
+                    ttl: entry.ttl,
+                    age: now.saturating_duration_since(entry.stored).as_secs(),
+                    hits: entry.hits,
+                    authenticated: entry.authenticated,
+                }
+            })
+            .collect();
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            saved_at: now_unix,
+            entries,
+        }
+    }
+
+    /// Puts the snapshot's entries into the cache, counting the time since it was taken against
+    /// their TTLs. Entries that expired past the stale window meanwhile, or that don't decode,
+    /// are left out.
+    fn restore(&self, snapshot: Snapshot, now: Instant, now_unix: u64) -> usize {
+        let downtime = now_unix.saturating_sub(snapshot.saved_at);
+        let mut state = self.state.lock().unwrap();
+        let mut restored = 0;
+        for saved in snapshot.entries {
+            let age = saved.age.saturating_add(downtime);
+            let Some(stored) = now.checked_sub(Duration::from_secs(age)) else {
+                continue;
+            };
+            let Some((key, data)) = decode_entry(&saved) else {
+                continue;
+            };
+            if age >= saved.ttl as u64 + state.stale_window as u64 {
+                continue;
+            }
+            let entry = CacheEntry::new(data, stored, saved.ttl, saved.hits, saved.authenticated);
+            state.add(key, entry);
+            restored += 1;
+        }
+        restored
+    }
+}
+
+fn decode_entry(saved: &SnapshotEntry) -> Option<(CacheKey, CacheData)> {
+    let (name, _) = decode_name(&saved.name, 0).ok()?;
+    let key = CacheKey {
+        name,
+        record_type: saved.record_type.into(),
+        class: saved.class.into(),
+    };
+    let mut records = saved
+        .records
+        .iter()
+        .map(|bytes| ResourceRecord::from_bytes(bytes, 0).map(|(record, _)| record))
+        .collect::<Result<Vec<_>, _>>()
+        .ok()?;
+    let data = match saved.negative_response_code {
+        None => CacheData::Records(records),
+        Some(response_code) => CacheData::Negative {
+            response_code: ResponseCode::from(response_code),
+            soa: records.pop()?,
+        },
+    };
+    Some((key, data))
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{a_record, negative_response, question, response, soa_record};
+    use super::super::CacheConfig;
+    use super::*;
+    use crate::dns_protocol::dns_field_codes::RecordType;
+
+    #[test]
+    fn test_restore_ages_entries_by_downtime() {
+        let cache = Cache::new(CacheConfig {
+            stale_window: 100,
+            ..Default::default()
+        });
+        let now = Instant::now();
+        let www = question("www.example.com", RecordType::A);
+        let missing = question("missing.example.com", RecordType::A);
+        let short = question("short.example.com", RecordType::A);
+        cache.insert_at(
+            &www,
+            &response(vec![a_record("www.example.com", 600, 1)]),
+            now,
+        );
+        cache.insert_at(
+            &missing,
+            &negative_response(
+                ResponseCode::NameError,
+                vec![],
+                soa_record("example.com", 3600, 300),
+            ),
+            now,
+        );
+        cache.insert_at(
+            &short,
+            &response(vec![a_record("short.example.com", 10, 1)]),
+            now,
+        );
+
+        let later = now + Duration::from_secs(50);
+        let snapshot = cache.snapshot(later, 1_000_000);
+        let bytes = rkyv::to_bytes::<rancor::Error>(&snapshot).unwrap();
+        let snapshot = rkyv::from_bytes::<Snapshot, rancor::Error>(&bytes).unwrap();
+        assert_eq!(snapshot.entries.len(), 3);
+
+        // Back up 70 seconds later: 120 seconds old in all, which is past the short entry's
+        // stale window
+        let restarted = Cache::new(CacheConfig {
+            stale_window: 100,
+            ..Default::default()
+        });
+        let start = Instant::now() + Duration::from_secs(1000);
+        assert_eq!(restarted.restore(snapshot, start, 1_000_070), 2);
+        let hit = restarted.get_at(&www, start, false).unwrap().response;
+        assert_eq!(hit.answers, [a_record("www.example.com", 480, 1)]);
+        let hit = restarted.get_at(&missing, start, false).unwrap().response;
+        assert_eq!(hit.header.response_code, ResponseCode::NameError);
+        assert_eq!(hit.authorities[0].ttl, 180);
+        assert_eq!(restarted.get_at(&short, start, true), None);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!("dns-cache-test-{}.rkyv", std::process::id()));
+        let cache = Cache::new(CacheConfig::default());
+        let www = question("www.example.com", RecordType::A);
+        cache.insert(&www, &response(vec![a_record("www.example.com", 600, 1)]));
+        assert_eq!(cache.save(&path).unwrap(), 1);
+
+        let restarted = Cache::new(CacheConfig::default());
+        assert_eq!(restarted.load(&path).unwrap(), 1);
+        let hit = restarted.get(&www).unwrap().response;
+        assert_eq!(
+            hit.answers[0].data,
+            a_record("www.example.com", 600, 1).data
+        );
+        assert!(hit.answers[0].ttl > 590);
+
+        fs::write(&path, b"not a snapshot").unwrap();
+        assert!(restarted.load(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_saves() {
+        let path = std::env::temp_dir().join(format!(
+            "dns-cache-concurrent-test-{}.rkyv",
+            std::process::id()
+        ));
+        let cache = Cache::new(CacheConfig::default());
+        for last_octet in 0..50 {
+            let name = format!("host{}.example.com", last_octet);
+            cache.insert(
+                &question(&name, RecordType::A),
+                &response(vec![a_record(&name, 600, last_octet)]),
+            );
+        }
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..20 {
+                        assert_eq!(cache.save(&path).unwrap(), 50);
+                    }
+                });
+            }
+        });
+        assert_eq!(Cache::new(CacheConfig::default()).load(&path).unwrap(), 50);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,61 +0,0 @@
-// #[derive(Copy, Clone)]
-// pub enum QueryResponseIndicator {
-//     Query = 0,
-//     Response = 1,
-// }
-
-// pub enum Opcode {
-//     StandardQuery = 0,
-//     InverseQuery = 1,
-//     ServerStatusRequest = 2,
-// }
-
-// pub enum AuthoritativeAnswer {
-//     NotAuthoritative = 0,
-//     Authoritative = 1,
-// }
-
-// pub enum Truncation {
-//     NotTruncated = 0,
-//     Truncated = 1,
-// }
-
-// pub enum RecursionDesired {
-//     RecursionNotDesired = 0,
-//     RecursionDesired = 1,
-// }
-
-// pub enum RecursionAvailable {
-//     RecursionNotAvailable = 0,
-//     RecursionAvailable = 1,
-// }
-
-// pub enum ResponseCode {
-//     NoError = 0,
-//     FormatError = 1,
-//     ServerFailure = 2,
-//     NameError = 3,
-//     NotImplemented = 4,
-//     Refused = 5,
-// }
-
-// pub enum AnswerQuestionType {
-//     A = 1,
-//     NS = 2,
-//     CNAME = 5,
-//     SOA = 6,
-//     PTR = 12,
-//     MX = 15,
-//     TXT = 16,
-//     AAAA = 28,
-//     SRV = 33,
-//     ANY = 255,
-// }
-
-// pub enum AnswerQuestionClass {
-//     IN = 1,
-//     CS = 2,
-//     CH = 3,
-//     HS = 4,
-//     ANY = 255,
-// }
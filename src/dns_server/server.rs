@@ -1,133 +1,895 @@
 use crate::dns_protocol::{
-    dns_header::DnsHeader, dns_header::DNS_HEADER_SIZE, dns_question::decode_questions,
-    dns_question::DnsQuestion, dns_resource_record::ResourceRecord,
+    dns_edns::{Edns, DEFAULT_UDP_PAYLOAD_SIZE},
+    dns_field_codes::{Opcode, ResponseCode},
+    dns_header::DnsHeader,
+    dns_header::DNS_HEADER_SIZE,
+    dns_message::DnsMessage,
+    dns_question::DnsQuestion,
 };
-use std::net::{SocketAddr, UdpSocket};
+use crate::dns_server::cache::{Cache, CacheConfig};
+use crate::dns_server::tcp;
+use crate::dns_server::thread_pool::ThreadPool;
+use crate::dns_server::upstream::{Forwarder, UpstreamConfig};
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The largest UDP payload we advertise, both to clients and to the upstream resolver. 1232
+/// avoids IP fragmentation on practically every path (DNS flag day 2020).
+pub(crate) const SERVER_UDP_PAYLOAD_SIZE: u16 = 1232;
+
+/// How long a TCP client may sit idle between queries before we close the connection
+/// (RFC 7766 section 6.2.3)
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// TCP connections beyond this are closed as soon as they're accepted
+const MAX_TCP_CONNECTIONS: usize = 64;
+
+/// Threads answering UDP queries, unless configured otherwise
+pub const DEFAULT_WORKERS: usize = 16;
+
+/// UDP queries allowed to wait for each worker before further ones are dropped
+const UDP_QUEUE_PER_WORKER: usize = 64;
+
+/// Threads refreshing cache entries in the background, both popular ones about to expire and
+/// expired ones whose lookup outlasted the client's patience, and how many refreshes may wait
+const REFRESH_WORKERS: usize = 4;
+const REFRESH_QUEUE_LENGTH: usize = 256;
+
+/// How often the cache's size and eviction count are logged
+const CACHE_STATS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Where the cache is kept across restarts. It's loaded on startup and saved every
+/// `save_interval`, and again when the process is told to stop.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheFile {
+    pub path: PathBuf,
+    pub save_interval: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Transport {
+    Udp,
+    Tcp,
+}
 
 pub struct Server {
     source_ip: String,
     port: u16,
-    forwarding_socket: UdpSocket,
-    resolver_addr: String,
-    client_response_buf: [u8; 1500],
-    client_receive_buf: [u8; 1500],
+    forwarder: Arc<Forwarder>,
+    cache: Arc<Cache>,
+    cache_file: Option<CacheFile>,
+    workers: usize,
+    refresh_pool: ThreadPool,
+    tcp_connections: AtomicUsize,
+    tcp_idle_timeout: Duration,
 }
 
 impl Server {
-    pub fn new(source_ip: String, port: u16, resolver_addr: String) -> Self {
+    pub fn new(
+        source_ip: String,
+        port: u16,
+        resolver_addr: String,
+        upstream_config: UpstreamConfig,
+        cache_config: CacheConfig,
+        cache_file: Option<CacheFile>,
+        workers: usize,
+    ) -> Self {
         Self {
             source_ip,
             port,
-            forwarding_socket: UdpSocket::bind("127.0.0.1:0")
-                .expect("Failed to bind to forwarding socket"),
-            resolver_addr,
-            client_response_buf: [0; 1500],
-            client_receive_buf: [0; 1500],
+            forwarder: Arc::new(Forwarder::new(resolver_addr, upstream_config)),
+            cache: Arc::new(Cache::new(cache_config)),
+            cache_file,
+            workers,
+            refresh_pool: ThreadPool::new(REFRESH_WORKERS, REFRESH_QUEUE_LENGTH),
+            tcp_connections: AtomicUsize::new(0),
+            tcp_idle_timeout: TCP_IDLE_TIMEOUT,
         }
     }
 
-    pub fn start(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let mut udp_socket = UdpSocket::bind(format!("{}:{}", self.source_ip, self.port))?;
+    /// Serves UDP and TCP on the same address. UDP packets are read on the calling thread and
+    /// answered on a pool of workers, so a slow upstream lookup doesn't hold up other clients;
+    /// each TCP connection gets a thread of its own.
+    pub fn start(self) -> Result<(), Box<dyn std::error::Error>> {
+        let address = format!("{}:{}", self.source_ip, self.port);
+        let udp_socket = Arc::new(UdpSocket::bind(&address)?);
+        let tcp_listener = TcpListener::bind(&address)?;
+        let pool = ThreadPool::new(self.workers, self.workers * UDP_QUEUE_PER_WORKER);
+        if let Some(cache_file) = &self.cache_file {
+            self.persist_cache(cache_file.clone());
+        }
+        let cache = Arc::clone(&self.cache);
+        thread::spawn(move || log_cache_stats(&cache));
+        let server = Arc::new(self);
+
+        let tcp_server = Arc::clone(&server);
+        thread::spawn(move || tcp_server.serve_tcp(tcp_listener));
+
+        let mut client_receive_buf = [0; 1500];
         loop {
-            match udp_socket.recv_from(&mut self.client_receive_buf) {
+            match udp_socket.recv_from(&mut client_receive_buf) {
                 Ok((size, source)) => {
-                    self.handle_packet(&mut udp_socket, &source, size)?;
+                    let packet = client_receive_buf[..size].to_vec();
+                    let server = Arc::clone(&server);
+                    let udp_socket = Arc::clone(&udp_socket);
+                    let queued = pool.try_execute(move || {
+                        if let Err(e) = server.handle_packet(&udp_socket, &source, &packet) {
+                            eprintln!("Error handling packet from {}: {}", source, e);
+                        }
+                    });
+                    if !queued {
+                        eprintln!("All workers busy, dropping packet from {}", source);
+                    }
                 }
                 Err(e) => {
                     eprintln!("Error receiving data: {}", e);
+                    if let Some(cache_file) = &server.cache_file {
+                        save_cache(&server.cache, &cache_file.path);
+                    }
                     return Err(e.into());
                 }
             }
         }
     }
 
+    /// Loads the cache from `cache_file`, then keeps saving it there on a background thread and
+    /// when the process receives Ctrl-C or SIGTERM
+    fn persist_cache(&self, cache_file: CacheFile) {
+        match self.cache.load(&cache_file.path) {
+            Ok(count) => println!(
+                "Loaded {} cache entries from {}",
+                count,
+                cache_file.path.display()
+            ),
+            Err(e) => eprintln!(
+                "Not loading cache from {}: {}",
+                cache_file.path.display(),
+                e
+            ),
+        }
+
+        let cache = Arc::clone(&self.cache);
+        let path = cache_file.path.clone();
+        thread::spawn(move || loop {
+            thread::sleep(cache_file.save_interval);
+            save_cache(&cache, &path);
+        });
+
+        let cache = Arc::clone(&self.cache);
+        let path = cache_file.path;
+        let handler = ctrlc::set_handler(move || {
+            save_cache(&cache, &path);
+            std::process::exit(0);
+        });
+        if let Err(e) = handler {
+            eprintln!("Cache won't be saved on shutdown: {}", e);
+        }
+    }
+
     fn handle_packet(
-        &mut self,
-        udp_socket: &mut UdpSocket,
+        &self,
+        udp_socket: &UdpSocket,
         source: &SocketAddr,
-        len: usize,
+        packet: &[u8],
     ) -> Result<(), anyhow::Error> {
-        let query_header =
-            DnsHeader::from_network_bytes(self.client_receive_buf[..DNS_HEADER_SIZE].try_into()?);
+        if let Some(response) = self.handle_query(source, packet, Transport::Udp)? {
+            udp_socket.send_to(&response, source)?;
+        }
+        Ok(())
+    }
+
+    fn serve_tcp(self: Arc<Self>, listener: TcpListener) {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("Error accepting TCP connection: {}", e);
+                    continue;
+                }
+            };
+            if self.tcp_connections.fetch_add(1, Ordering::SeqCst) >= MAX_TCP_CONNECTIONS {
+                self.tcp_connections.fetch_sub(1, Ordering::SeqCst);
+                eprintln!(
+                    "Too many TCP connections, dropping {:?}",
+                    stream.peer_addr()
+                );
+                continue;
+            }
+            let server = Arc::clone(&self);
+            thread::spawn(move || {
+                if let Err(e) = server.handle_connection(stream) {
+                    eprintln!("Error handling TCP connection: {}", e);
+                }
+                server.tcp_connections.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    }
+
+    /// Answers queries on a TCP connection in the order they arrive until the client closes it
+    /// or goes idle. Clients may pipeline several queries without waiting for each answer. Each
+    /// query has to arrive in full within the idle timeout of the previous answer.
+    fn handle_connection(&self, mut stream: TcpStream) -> Result<(), anyhow::Error> {
+        let source = stream.peer_addr()?;
+        stream.set_write_timeout(Some(self.tcp_idle_timeout))?;
+        loop {
+            let deadline = Instant::now() + self.tcp_idle_timeout;
+            let query = match tcp::read_message(&mut tcp::DeadlineReader::new(&stream, deadline)) {
+                Ok(Some(query)) => query,
+                Ok(None) => return Ok(()),
+                Err(e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) => {
+                    println!("Closing idle TCP connection from {}", source);
+                    return Ok(());
+                }
+                Err(e) => return Err(e.into()),
+            };
+            match self.handle_query(&source, &query, Transport::Tcp)? {
+                Some(response) => tcp::write_message(&mut stream, &response)?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// The transport-independent part of answering a query. Returns `None` if the message
+    /// should be dropped without a reply.
+    fn handle_query(
+        &self,
+        source: &SocketAddr,
+        query_bytes: &[u8],
+        transport: Transport,
+    ) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        let query = match DnsMessage::from_bytes(query_bytes) {
+            Ok(query) => query,
+            Err(e) => {
+                eprintln!("Malformed query from {}: {}", source, e);
+                return Ok(format_error_response(query_bytes));
+            }
+        };
+        // Answering a response could start an endless exchange with another server, or with us
+        if query.header.query_response_indicator {
+            eprintln!("Dropping response from {}", source);
+            return Ok(None);
+        }
+
+        let max_response_size = match transport {
+            Transport::Udp => query
+                .edns
+                .as_ref()
+                .map_or(DEFAULT_UDP_PAYLOAD_SIZE, |edns| {
+                    edns.udp_payload_size.min(SERVER_UDP_PAYLOAD_SIZE)
+                }),
+            Transport::Tcp => u16::MAX,
+        } as usize;
+        let dnssec_ok = query.edns.as_ref().is_some_and(|edns| edns.dnssec_ok);
+
+        let mut response_header = query.header;
+        response_header.query_response_indicator = true;
+        // Z has to be zero and AD waits on what upstream says, but CD is echoed (RFC 4035)
+        response_header.reserved = 0;
+        response_header.set_checking_disabled(query.header.checking_disabled());
+        response_header.response_code = if response_header.opcode == Opcode::Query {
+            ResponseCode::NoError
+        } else {
+            ResponseCode::NotImplemented
+        };
+        let mut response = DnsMessage {
+            header: response_header,
+            questions: query.questions.clone(),
+            edns: query.edns.as_ref().map(|_| Edns {
+                udp_payload_size: SERVER_UDP_PAYLOAD_SIZE,
+                dnssec_ok,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        // We only speak EDNS version 0 (RFC 6891 section 6.1.3)
+        if query.edns.as_ref().is_some_and(|edns| edns.version > 0) {
+            response.header.response_code = ResponseCode::BadVersion;
+            return Ok(Some(response.to_truncated_bytes(max_response_size)));
+        }
+
+        if response.header.opcode != Opcode::Query {
+            return Ok(Some(response.to_truncated_bytes(max_response_size)));
+        }
+
+        match self.resolve(&query, dnssec_ok) {
+            Ok(upstream) => {
+                response.header.response_code = upstream.header.response_code;
+                response.header.authoritative_answer = upstream.header.authoritative_answer;
+                response.header.recursion_available = upstream.header.recursion_available;
+                // Only for clients that showed they understand it (RFC 6840 section 5.8)
+                response.header.set_authentic_data(
+                    upstream.header.authentic_data()
+                        && (dnssec_ok || query.header.authentic_data()),
+                );
+                response.answers = upstream.answers;
+                response.authorities = upstream.authorities;
+                response.additionals = upstream.additionals;
+            }
+            Err(e) => {
+                eprintln!("Forwarding failed: {}", e);
+                response.header.response_code = ResponseCode::ServerFailure;
+            }
+        }
+        println!(
+            "Handle Packet - Received {} answers",
+            response.answers.len()
+        );
+
+        Ok(Some(response.to_truncated_bytes(max_response_size)))
+    }
 
-        let query_questions = decode_questions(
-            &self.client_receive_buf[DNS_HEADER_SIZE..len],
-            query_header.question_count,
+    /// Answers each question from the cache if possible and from upstream if not, combining
+    /// the results: every record from every section, plus the flags and response code.
+    fn resolve(&self, query: &DnsMessage, dnssec_ok: bool) -> Result<DnsMessage, anyhow::Error> {
+        let mut combined: Option<DnsMessage> = None;
+        for question in &query.questions {
+            let response = self.resolve_question(question, &query.header, dnssec_ok)?;
+            match &mut combined {
+                Some(combined) => combine_responses(combined, response),
+                None => combined = Some(response),
+            }
+        }
+        Ok(combined.unwrap_or_default())
+    }
+
+    /// Queries with CD set skip the cache both ways, since their answers haven't been
+    /// validated. Queries with DO set always go upstream too, as the cache doesn't keep the
+    /// RRSIGs and NSEC records they need, but what comes back is still cached for everyone else.
+    /// An expired answer still within the stale window is served if upstream fails, takes longer
+    /// than the client response timeout, failed for the same question recently, or is already
+    /// being asked on behalf of another client. DO queries only get one once upstream fails.
+    fn resolve_question(
+        &self,
+        question: &DnsQuestion,
+        query_header: &DnsHeader,
+        dnssec_ok: bool,
+    ) -> Result<DnsMessage, anyhow::Error> {
+        let recursion_desired = query_header.recursion_desired;
+        let checking_disabled = query_header.checking_disabled();
+        if checking_disabled || dnssec_ok {
+            let result = self.forwarder.forward_query(
+                question,
+                recursion_desired,
+                dnssec_ok,
+                checking_disabled,
+            );
+            if checking_disabled {
+                return result;
+            }
+            match result {
+                Ok(response) if !is_error_answer(&response) => {
+                    self.cache.insert(question, &response);
+                    return Ok(response);
+                }
+                // Unsigned, but a validating client is no worse off than with SERVFAIL
+                result => {
+                    return match self.cache.get_stale(question) {
+                        Some(stale) => {
+                            eprintln!(
+                                "Upstream failed for {}, serving stale answer",
+                                question.domain_name
+                            );
+                            Ok(stale.response)
+                        }
+                        None => result,
+                    }
+                }
+            }
+        }
+
+        if let Some(hit) = self.cache.get(question) {
+            if hit.prefetch {
+                self.prefetch(question);
+            }
+            return Ok(hit.response);
+        }
+        let Some(stale) = self.cache.get_stale(question) else {
+            let response =
+                self.forwarder
+                    .forward_query(question, recursion_desired, false, false)?;
+            self.cache.insert(question, &response);
+            return Ok(response);
+        };
+
+        // Better an old answer than none at all, or than one that comes too late (RFC 8767
+        // section 5)
+        if self.cache.failed_recently(question) {
+            println!(
+                "Lookup for {} failed recently, serving stale answer",
+                question.domain_name
+            );
+            return Ok(stale.response);
+        }
+        if !self.cache.start_refresh(question) {
+            println!(
+                "Refresh for {} already under way, serving stale answer",
+                question.domain_name
+            );
+            return Ok(stale.response);
+        }
+        let (sender, receiver) = mpsc::channel();
+        let forwarder = Arc::clone(&self.forwarder);
+        let cache = Arc::clone(&self.cache);
+        let refresh_question = question.clone();
+        let queued = self.refresh_pool.try_execute(move || {
+            let result =
+                forwarder.forward_query(&refresh_question, recursion_desired, false, false);
+            match &result {
+                Ok(response) if !is_error_answer(response) => {
+                    cache.insert(&refresh_question, response)
+                }
+                _ => cache.record_failure(&refresh_question),
+            }
+            cache.finish_refresh(&refresh_question);
+            // Nobody's listening if the client has already had the stale answer
+            let _ = sender.send(result);
+        });
+        if !queued {
+            self.cache.finish_refresh(question);
+            eprintln!(
+                "Refresh queue full, serving stale answer for {}",
+                question.domain_name
+            );
+            return Ok(stale.response);
+        }
+        match receiver.recv_timeout(self.cache.config().client_response_timeout) {
+            Ok(Ok(response)) if !is_error_answer(&response) => return Ok(response),
+            Ok(Ok(response)) => eprintln!(
+                "Upstream answered {} for {}, serving stale answer",
+                response.header.response_code, question.domain_name
+            ),
+            Ok(Err(e)) => eprintln!("{}; serving stale answer for {}", e, question.domain_name),
+            Err(_) => println!(
+                "Still waiting on upstream for {}, serving stale answer",
+                question.domain_name
+            ),
+        }
+        Ok(stale.response)
+    }
+
+    /// Refreshes the cached answer to a popular question in the background, so it's replaced
+    /// before it expires rather than after
+    fn prefetch(&self, question: &DnsQuestion) {
+        let forwarder = Arc::clone(&self.forwarder);
+        let cache = Arc::clone(&self.cache);
+        let job_question = question.clone();
+        let queued = self.refresh_pool.try_execute(move || {
+            let question = job_question;
+            match forwarder.forward_query(&question, true, false, false) {
+                Ok(response) if !is_error_answer(&response) => cache.insert(&question, &response),
+                Ok(response) => {
+                    eprintln!(
+                        "Prefetch for {} got {:?}",
+                        question.domain_name, response.header.response_code
+                    );
+                    cache.prefetch_failed(&question);
+                }
+                Err(e) => {
+                    eprintln!("Prefetch for {} failed: {}", question.domain_name, e);
+                    cache.prefetch_failed(&question);
+                }
+            }
+        });
+        if !queued {
+            eprintln!(
+                "Prefetch queue full, not refreshing {}",
+                question.domain_name
+            );
+            self.cache.prefetch_failed(question);
+        }
+    }
+}
+
+/// Builds a bare FORMERR reply for a query we couldn't parse, as long as there's at least a
+/// header to take the ID from. Malformed responses are dropped rather than answered.
+fn format_error_response(query_bytes: &[u8]) -> Option<Vec<u8>> {
+    let header =
+        DnsHeader::from_network_bytes(query_bytes.get(..DNS_HEADER_SIZE)?.try_into().ok()?);
+    if header.query_response_indicator {
+        return None;
+    }
+    let response = DnsMessage {
+        header: DnsHeader {
+            query_response_indicator: true,
+            response_code: ResponseCode::FormatError,
+            ..header
+        },
+        ..Default::default()
+    };
+    Some(response.to_bytes())
+}
+
+/// Whether upstream answered with an error, such as SERVFAIL when it can't reach the
+/// authoritative servers. RFC 8767 treats that as a failed lookup, just like no answer at all.
+fn is_error_answer(response: &DnsMessage) -> bool {
+    !matches!(
+        response.header.response_code,
+        ResponseCode::NoError | ResponseCode::NameError
+    )
+}
+
+/// Writes the cache to `path`, logging how that went rather than failing, since the server can
+/// carry on without it
+fn save_cache(cache: &Cache, path: &Path) {
+    match cache.save(path) {
+        Ok(count) => println!("Saved {} cache entries to {}", count, path.display()),
+        Err(e) => eprintln!("Error saving cache to {}: {}", path.display(), e),
+    }
+}
+
+/// Logs how full the cache is every `CACHE_STATS_INTERVAL`, so it's clear whether the byte
+/// limit is being hit
+fn log_cache_stats(cache: &Cache) {
+    loop {
+        thread::sleep(CACHE_STATS_INTERVAL);
+        let stats = cache.stats();
+        println!(
+            "Cache holds {} entries in {} of {} bytes, {} evicted so far",
+            stats.entries,
+            stats.bytes,
+            cache.config().max_bytes,
+            stats.evictions
+        );
+    }
+}
+
+/// Adds a reply to another question onto `combined`. The first error code any reply carries wins,
+/// and the answer is only authoritative, or authenticated, if every part of it was.
+fn combine_responses(combined: &mut DnsMessage, response: DnsMessage) {
+    if combined.header.response_code == ResponseCode::NoError {
+        combined.header.response_code = response.header.response_code;
+    }
+    combined.header.authoritative_answer &= response.header.authoritative_answer;
+    combined.header.recursion_available &= response.header.recursion_available;
+    combined
+        .header
+        .set_authentic_data(combined.header.authentic_data() && response.header.authentic_data());
+    combined.header.set_checking_disabled(
+        combined.header.checking_disabled() || response.header.checking_disabled(),
+    );
+    combined.questions.extend(response.questions);
+    combined.answers.extend(response.answers);
+    combined.authorities.extend(response.authorities);
+    combined.additionals.extend(response.additionals);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_protocol::{
+        dns_field_codes::{RecordClass, RecordType},
+        dns_header::{AUTHENTIC_DATA, CHECKING_DISABLED},
+        dns_resource_record::{RData, ResourceRecord},
+    };
+    use std::io::Write;
+    use std::net::Ipv4Addr;
+    use std::sync::mpsc;
+
+    /// A resolver on a local UDP port that answers each query with whatever `reply` makes of
+    /// it, or not at all for `None`. It stops after a few idle seconds.
+    fn stub_resolver(reply: impl Fn(&DnsMessage) -> Option<DnsMessage> + Send + 'static) -> String {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let address = socket.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let mut buf = [0; 1500];
+            while let Ok((len, source)) = socket.recv_from(&mut buf) {
+                let query = DnsMessage::from_bytes(&buf[..len]).unwrap();
+                if let Some(response) = reply(&query) {
+                    socket.send_to(&response.to_bytes(), source).unwrap();
+                }
+            }
+        });
+        address
+    }
+
+    /// An empty reply to `query` for a stub resolver to fill in
+    fn reply_to(query: &DnsMessage) -> DnsMessage {
+        DnsMessage {
+            header: DnsHeader {
+                query_response_indicator: true,
+                recursion_available: true,
+                ..query.header
+            },
+            questions: query.questions.clone(),
+            ..Default::default()
+        }
+    }
+
+    fn test_server(
+        resolver_addr: String,
+        upstream_config: UpstreamConfig,
+        cache_config: CacheConfig,
+    ) -> Server {
+        Server::new(
+            "127.0.0.1".to_string(),
+            0,
+            resolver_addr,
+            upstream_config,
+            cache_config,
+            None,
+            1,
         )
-        .expect("Failed to decode questions in client query");
-
-        let mut response_header = query_header;
-        response_header.query_response_indicator = 1;
-        response_header.response_code = if response_header.opcode == 0 { 0 } else { 4 };
-        response_header.answer_record_count = response_header.question_count;
-        self.client_response_buf[0..DNS_HEADER_SIZE]
-            .copy_from_slice(&response_header.to_network_bytes());
-
-        let answers = self.forward_query(query_questions.as_slice())?;
-        println!("Handle Packet - Received {} answers", answers.len());
-        let mut question_index = DNS_HEADER_SIZE;
-        for question in query_questions {
-            let mut response_question = question;
-            response_question.question_type = 1;
-            response_question.class = 1;
-            let response_question_bytes = response_question.to_bytes();
-            self.client_response_buf
-                [question_index..question_index + response_question_bytes.len()]
-                .copy_from_slice(response_question_bytes.as_slice());
-            question_index += response_question_bytes.len();
-        }
-
-        let mut answer_index = question_index;
-        for answer in answers {
-            let answer_bytes = answer.to_bytes();
-            self.client_response_buf[answer_index..answer_index + answer_bytes.len()]
-                .copy_from_slice(answer_bytes.as_slice());
-            answer_index += answer_bytes.len();
-        }
-        udp_socket.send_to(&self.client_response_buf[..answer_index], source)?;
-        Ok(())
     }
 
-    fn forward_query(
-        &mut self,
-        query_questions: &[DnsQuestion],
-    ) -> Result<Vec<ResourceRecord>, anyhow::Error> {
-        let mut resource_records = Vec::<ResourceRecord>::new();
-        //add udp socket to self (might be able to reuse current one)
-        //same with buffers (could maybe reuse)
-        let mut forwarding_buf = [0; 1500];
-        let mut receive_buf: [u8; 1500] = [0; 1500];
-        for (id, question) in query_questions.iter().enumerate() {
-            let header = DnsHeader {
-                packet_identifier: id as u16,
-                question_count: 1,
+    fn a_query(name: &str) -> DnsMessage {
+        DnsMessage {
+            header: DnsHeader {
+                recursion_desired: true,
                 ..Default::default()
-            };
-            println!("Forwarding query {:?}", question);
-            let header_bytes = header.to_network_bytes();
-            let question_bytes = question.to_bytes();
-            forwarding_buf[..DNS_HEADER_SIZE].copy_from_slice(&header_bytes);
-            forwarding_buf[DNS_HEADER_SIZE..DNS_HEADER_SIZE + question_bytes.len()]
-                .copy_from_slice(question_bytes.as_slice());
-            self.forwarding_socket.send_to(
-                &forwarding_buf[..DNS_HEADER_SIZE + question_bytes.len()],
-                self.resolver_addr.to_string(),
-            )?;
-
-            let (len, _) = self.forwarding_socket.recv_from(&mut receive_buf)?;
-            let response_header =
-                DnsHeader::from_network_bytes(receive_buf[..DNS_HEADER_SIZE].try_into()?);
-            let response_questions = decode_questions(
-                &receive_buf[DNS_HEADER_SIZE..],
-                response_header.question_count,
-            )
-            .expect("Failed to decode questions in response from forwarder");
-            let response_answer = ResourceRecord::from_bytes(
-                &receive_buf[DNS_HEADER_SIZE + response_questions[0].to_bytes().len()..len], //assuming it's one question + one answer for forwarder
-            )
-            .expect("Failed to decode answers in forwarding service response");
-            resource_records.push(response_answer);
+            },
+            questions: vec![DnsQuestion {
+                domain_name: name.parse().unwrap(),
+                question_type: RecordType::A,
+                class: RecordClass::In,
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_responses_dropped() {
+        let resolver = stub_resolver(|query| Some(reply_to(query)));
+        let server = test_server(resolver, UpstreamConfig::default(), CacheConfig::default());
+        let source = "127.0.0.1:5353".parse().unwrap();
+        let mut message = a_query("www.example.com");
+        assert!(server
+            .handle_query(&source, &message.to_bytes(), Transport::Udp)
+            .unwrap()
+            .is_some());
+
+        message.header.query_response_indicator = true;
+        for transport in [Transport::Udp, Transport::Tcp] {
+            assert_eq!(
+                server
+                    .handle_query(&source, &message.to_bytes(), transport)
+                    .unwrap(),
+                None
+            );
         }
-        Ok(resource_records)
+    }
+
+    #[test]
+    fn test_tcp_connection() {
+        let resolver = stub_resolver(|query| Some(reply_to(query)));
+        let mut server = test_server(resolver, UpstreamConfig::default(), CacheConfig::default());
+        server.tcp_idle_timeout = Duration::from_millis(300);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+
+        thread::scope(|scope| {
+            let (finished, wait_finished) = mpsc::channel();
+            let server = &server;
+            scope.spawn(move || {
+                server.handle_connection(accepted).unwrap();
+                finished.send(()).unwrap();
+            });
+
+            // Two queries in one write, answered in order
+            let mut pipelined = Vec::new();
+            for id in [1, 2] {
+                let mut query = a_query("www.example.com");
+                query.header.packet_identifier = id;
+                tcp::write_message(&mut pipelined, &query.to_bytes()).unwrap();
+            }
+            client.write_all(&pipelined).unwrap();
+            for id in [1, 2] {
+                let response = tcp::read_message(&mut client).unwrap().unwrap();
+                let response = DnsMessage::from_bytes(&response).unwrap();
+                assert_eq!(response.header.packet_identifier, id);
+            }
+
+            // A byte at a time, each well inside the idle timeout, still gets cut off
+            let started = Instant::now();
+            client.write_all(&[0, 40]).unwrap();
+            let closed = (0..20).any(|_| {
+                let _ = client.write_all(&[0]);
+                thread::sleep(Duration::from_millis(100));
+                wait_finished.try_recv().is_ok()
+            });
+            assert!(closed);
+            assert!(started.elapsed() < Duration::from_secs(1));
+            // Closed without an answer, possibly with a reset for the unread bytes
+            assert!(!matches!(tcp::read_message(&mut client), Ok(Some(_))));
+        });
+    }
+
+    #[test]
+    fn test_dnssec_queries_bypass_cache() {
+        let rrsig = ResourceRecord::new(
+            "www.example.com".parse().unwrap(),
+            RecordType::from(46),
+            RecordClass::In,
+            60,
+            RData::Unknown(vec![0; 18]),
+        );
+        let signature = rrsig.clone();
+        let resolver = stub_resolver(move |query| {
+            let mut reply = reply_to(query);
+            reply.answers.push(ResourceRecord::new(
+                "www.example.com".parse().unwrap(),
+                RecordType::A,
+                RecordClass::In,
+                60,
+                RData::A(Ipv4Addr::LOCALHOST),
+            ));
+            if query.edns.as_ref().is_some_and(|edns| edns.dnssec_ok) {
+                reply.answers.push(signature.clone());
+            }
+            Some(reply)
+        });
+        let server = test_server(resolver, UpstreamConfig::default(), CacheConfig::default());
+        let query = a_query("www.example.com");
+
+        let unsigned = server.resolve(&query, false).unwrap();
+        assert_eq!(unsigned.answers.len(), 1);
+        assert_eq!(server.cache.len(), 1);
+
+        // Answered from upstream rather than by the unsigned cache entry
+        let signed = server.resolve(&query, true).unwrap();
+        assert_eq!(signed.answers.len(), 2);
+        assert!(signed.answers.contains(&rrsig));
+    }
+
+    #[test]
+    fn test_stale_answer_when_upstream_is_slow_or_failing() {
+        // Answers the first query with a 1 second TTL, then stops answering
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&queries);
+        let resolver = stub_resolver(move |query| {
+            if counted.fetch_add(1, Ordering::SeqCst) > 0 {
+                return None;
+            }
+            let mut reply = reply_to(query);
+            reply.answers.push(ResourceRecord::new(
+                "www.example.com".parse().unwrap(),
+                RecordType::A,
+                RecordClass::In,
+                1,
+                RData::A(Ipv4Addr::LOCALHOST),
+            ));
+            Some(reply)
+        });
+        let upstream_config = UpstreamConfig {
+            timeout: Duration::from_millis(500),
+            retries: 0,
+        };
+        let cache_config = CacheConfig {
+            client_response_timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let server = test_server(resolver, upstream_config, cache_config);
+        let query = a_query("www.example.com");
+        assert_eq!(server.resolve(&query, false).unwrap().answers[0].ttl, 1);
+        thread::sleep(Duration::from_millis(1100));
+
+        // Served stale once the client response timeout is up, not the upstream timeout
+        let started = Instant::now();
+        let stale = server.resolve(&query, false).unwrap();
+        assert!(started.elapsed() < upstream_config.timeout);
+        assert_eq!(stale.answers[0].ttl, cache_config.stale_answer_ttl);
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+
+        // The refresh is still running, so another client doesn't wait on one of its own
+        let started = Instant::now();
+        let stale = server.resolve(&query, false).unwrap();
+        assert!(started.elapsed() < cache_config.client_response_timeout);
+        assert_eq!(stale.answers[0].ttl, cache_config.stale_answer_ttl);
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+
+        // Once the refresh has failed, upstream isn't asked again for a while
+        thread::sleep(upstream_config.timeout);
+        let stale = server.resolve(&query, false).unwrap();
+        assert_eq!(stale.answers[0].ttl, cache_config.stale_answer_ttl);
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_stale_answer_when_upstream_answers_servfail() {
+        // Answers the first query with a 1 second TTL, then only with SERVFAIL
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&queries);
+        let resolver = stub_resolver(move |query| {
+            let mut reply = reply_to(query);
+            if counted.fetch_add(1, Ordering::SeqCst) > 0 {
+                reply.header.response_code = ResponseCode::ServerFailure;
+                return Some(reply);
+            }
+            reply.answers.push(ResourceRecord::new(
+                "www.example.com".parse().unwrap(),
+                RecordType::A,
+                RecordClass::In,
+                1,
+                RData::A(Ipv4Addr::LOCALHOST),
+            ));
+            Some(reply)
+        });
+        let cache_config = CacheConfig::default();
+        let server = test_server(resolver, UpstreamConfig::default(), cache_config);
+        let query = a_query("www.example.com");
+        assert_eq!(server.resolve(&query, false).unwrap().answers[0].ttl, 1);
+        thread::sleep(Duration::from_millis(1100));
+
+        let stale = server.resolve(&query, false).unwrap();
+        assert_eq!(stale.header.response_code, ResponseCode::NoError);
+        assert_eq!(stale.answers[0].ttl, cache_config.stale_answer_ttl);
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+
+        // The SERVFAIL counts as a failure, so upstream isn't asked again for a while
+        let stale = server.resolve(&query, false).unwrap();
+        assert_eq!(stale.answers[0].ttl, cache_config.stale_answer_ttl);
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+
+        // DO queries always ask, but get the stale answer rather than the SERVFAIL
+        let stale = server.resolve(&query, true).unwrap();
+        assert_eq!(stale.header.response_code, ResponseCode::NoError);
+        assert_eq!(stale.answers[0].ttl, cache_config.stale_answer_ttl);
+        assert_eq!(queries.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_combine_responses() {
+        let record = |name: &str| {
+            ResourceRecord::new(
+                name.parse().unwrap(),
+                RecordType::A,
+                RecordClass::In,
+                60,
+                RData::A(Ipv4Addr::LOCALHOST),
+            )
+        };
+        let mut combined = DnsMessage {
+            header: DnsHeader {
+                authoritative_answer: true,
+                recursion_available: true,
+                reserved: AUTHENTIC_DATA,
+                ..Default::default()
+            },
+            answers: vec![record("a.example"), record("a.example")],
+            ..Default::default()
+        };
+        let nxdomain = DnsMessage {
+            header: DnsHeader {
+                recursion_available: true,
+                reserved: AUTHENTIC_DATA | CHECKING_DISABLED,
+                response_code: ResponseCode::NameError,
+                ..Default::default()
+            },
+            authorities: vec![record("example")],
+            ..Default::default()
+        };
+        combine_responses(&mut combined, nxdomain);
+        assert!(combined.header.authentic_data());
+        assert!(combined.header.checking_disabled());
+        let servfail = DnsMessage {
+            header: DnsHeader {
+                response_code: ResponseCode::ServerFailure,
+                ..Default::default()
+            },
+            additionals: vec![record("c.example")],
+            ..Default::default()
+        };
+        combine_responses(&mut combined, servfail);
+
+        assert_eq!(combined.header.response_code, ResponseCode::NameError);
+        assert!(!combined.header.authoritative_answer);
+        assert!(!combined.header.recursion_available);
+        assert!(!combined.header.authentic_data());
+        assert!(combined.header.checking_disabled());
+        assert_eq!(combined.answers.len(), 2);
+        assert_eq!(combined.authorities, [record("example")]);
+        assert_eq!(combined.additionals, [record("c.example")]);
     }
 }
@@ -0,0 +1,93 @@
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::Instant;
+
+/// Reads from a stream until a fixed deadline, however the data trickles in. A plain read
+/// timeout only bounds each `read` call, so a peer sending a byte at a time could go on forever.
+pub struct DeadlineReader<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl<'a> DeadlineReader<'a> {
+    pub fn new(stream: &'a TcpStream, deadline: Instant) -> Self {
+        Self { stream, deadline }
+    }
+}
+
+impl Read for DeadlineReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        self.stream.read(buf)
+    }
+}
+
+/// Reads one message framed with the two-byte length prefix DNS uses over TCP (RFC 1035
+/// section 4.2.2). Returns `None` if the peer closed the connection between messages.
+pub fn read_message(stream: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut length = [0; 2];
+    let mut read = 0;
+    while read < length.len() {
+        match stream.read(&mut length[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    let mut message = vec![0; u16::from_be_bytes(length) as usize];
+    stream.read_exact(&mut message)?;
+    Ok(Some(message))
+}
+
+/// Writes `message` with its length prefix in a single write, so the prefix doesn't go out in a
+/// segment of its own.
+pub fn write_message(stream: &mut impl Write, message: &[u8]) -> io::Result<()> {
+    let length = u16::try_from(message.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "message over 65535 bytes"))?;
+    let mut framed = Vec::with_capacity(message.len() + 2);
+    framed.extend_from_slice(&length.to_be_bytes());
+    framed.extend_from_slice(message);
+    stream.write_all(&framed)?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_pipelined_messages() {
+        let mut stream = Vec::new();
+        write_message(&mut stream, &[1, 2, 3]).unwrap();
+        write_message(&mut stream, &[]).unwrap();
+        write_message(&mut stream, &[4; 300]).unwrap();
+        assert_eq!(stream[..5], [0, 3, 1, 2, 3]);
+
+        let mut stream = Cursor::new(stream);
+        assert_eq!(read_message(&mut stream).unwrap(), Some(vec![1, 2, 3]));
+        assert_eq!(read_message(&mut stream).unwrap(), Some(vec![]));
+        assert_eq!(read_message(&mut stream).unwrap(), Some(vec![4; 300]));
+        assert_eq!(read_message(&mut stream).unwrap(), None);
+    }
+
+    #[test]
+    fn test_read_truncated_message() {
+        let error = read_message(&mut Cursor::new([0])).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        let error = read_message(&mut Cursor::new([0, 4, 1, 2])).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_write_oversized_message() {
+        let error = write_message(&mut Vec::new(), &[0; 65536]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,105 @@
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed set of worker threads fed from a bounded queue. When the queue is full new work is
+/// turned away rather than piling up, which is the right call for UDP queries the client will
+/// retry anyway.
+pub struct ThreadPool {
+    sender: Option<SyncSender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl ThreadPool {
+    pub fn new(workers: usize, queue_length: usize) -> Self {
+        assert!(workers > 0, "a thread pool needs at least one worker");
+        let (sender, receiver) = mpsc::sync_channel::<Job>(queue_length);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..workers)
+            .map(|id| {
+                let receiver = Arc::clone(&receiver);
+                thread::Builder::new()
+                    .name(format!("worker-{}", id))
+                    .spawn(move || run_worker(&receiver))
+                    .expect("Failed to spawn worker thread")
+            })
+            .collect();
+        Self {
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    /// Queues `job` for the next free worker. Returns false, dropping the job, if the queue is
+    /// full.
+    pub fn try_execute(&self, job: impl FnOnce() + Send + 'static) -> bool {
+        let sender = self.sender.as_ref().expect("sender is only taken on drop");
+        match sender.try_send(Box::new(job)) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    /// Lets the workers finish what's queued, then waits for them to exit
+    fn drop(&mut self) {
+        self.sender.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn run_worker(receiver: &Mutex<Receiver<Job>>) {
+    loop {
+        // The lock is released as soon as a job is taken, before running it
+        let job = match receiver.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        job();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Barrier;
+
+    #[test]
+    fn test_jobs_run_concurrently() {
+        let pool = ThreadPool::new(4, 16);
+        let barrier = Arc::new(Barrier::new(4));
+        let finished = Arc::new(AtomicUsize::new(0));
+        for _ in 0..4 {
+            let barrier = Arc::clone(&barrier);
+            let finished = Arc::clone(&finished);
+            // Would deadlock if the jobs ran one at a time
+            assert!(pool.try_execute(move || {
+                barrier.wait();
+                finished.fetch_add(1, Ordering::SeqCst);
+            }));
+        }
+        drop(pool);
+        assert_eq!(finished.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn test_full_queue_rejects_jobs() {
+        let pool = ThreadPool::new(1, 1);
+        let (release, blocked) = mpsc::channel::<()>();
+        let (started, wait_started) = mpsc::channel();
+        assert!(pool.try_execute(move || {
+            started.send(()).unwrap();
+            blocked.recv().unwrap();
+        }));
+        wait_started.recv().unwrap();
+        assert!(pool.try_execute(|| {}));
+        assert!(!pool.try_execute(|| {}));
+        release.send(()).unwrap();
+    }
+}
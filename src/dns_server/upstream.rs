@@ -0,0 +1,492 @@
+use crate::dns_protocol::{
+    dns_edns::Edns,
+    dns_header::DnsHeader,
+    dns_message::DnsMessage,
+    dns_name::{DomainName, Label},
+    dns_question::DnsQuestion,
+};
+use crate::dns_server::{server::SERVER_UDP_PAYLOAD_SIZE, tcp};
+use rand::Rng;
+use std::collections::HashSet;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Source ports are picked from here up, leaving the well-known ports alone
+const MIN_SOURCE_PORT: u16 = 1024;
+
+/// Random ports to try before letting the OS choose one
+const SOURCE_PORT_BIND_ATTEMPTS: usize = 8;
+
+/// How hard to try the upstream resolver before giving up and answering SERVFAIL
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpstreamConfig {
+    /// How long to wait for a reply to the first attempt. Each retry waits twice as long as the
+    /// attempt before it.
+    pub timeout: Duration,
+    /// Attempts made after the first one times out or fails
+    pub retries: u32,
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_millis(2000),
+            retries: 2,
+        }
+    }
+}
+
+impl UpstreamConfig {
+    /// The timeout for each attempt in turn
+    fn attempt_timeouts(&self) -> impl Iterator<Item = Duration> + '_ {
+        (0..=self.retries).map(|attempt| self.timeout.saturating_mul(1 << attempt.min(31)))
+    }
+}
+
+/// Sends queries on to the upstream resolver and only accepts replies that match them. Any
+/// number of threads can forward through it at once.
+pub struct Forwarder {
+    resolver_addr: String,
+    config: UpstreamConfig,
+    /// IDs of the upstream queries currently waiting for a reply
+    outstanding: Mutex<HashSet<u16>>,
+}
+
+/// Holds an ID in `Forwarder::outstanding` until the query using it is finished with
+struct OutstandingQuery<'a> {
+    id: u16,
+    outstanding: &'a Mutex<HashSet<u16>>,
+}
+
+impl Drop for OutstandingQuery<'_> {
+    fn drop(&mut self) {
+        self.outstanding.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl Forwarder {
+    pub fn new(resolver_addr: String, config: UpstreamConfig) -> Self {
+        Self {
+            resolver_addr,
+            config,
+            outstanding: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Picks a random ID that no other in-flight upstream query is using
+    fn reserve_id(&self) -> OutstandingQuery<'_> {
+        let mut outstanding = self.outstanding.lock().unwrap();
+        loop {
+            let id = rand::random();
+            if outstanding.insert(id) {
+                return OutstandingQuery {
+                    id,
+                    outstanding: &self.outstanding,
+                };
+            }
+        }
+    }
+
+    /// Asks the resolver one question and returns its whole reply, with the question name in
+    /// the casing it was asked in. `checking_disabled` passes on a client's CD bit, asking the
+    /// resolver not to validate.
+    pub fn forward_query(
+        &self,
+        question: &DnsQuestion,
+        recursion_desired: bool,
+        dnssec_ok: bool,
+        checking_disabled: bool,
+    ) -> Result<DnsMessage, anyhow::Error> {
+        let reserved_id = self.reserve_id();
+        let mut header = DnsHeader {
+            packet_identifier: reserved_id.id,
+            recursion_desired,
+            ..Default::default()
+        };
+        header.set_checking_disabled(checking_disabled);
+        let query = DnsMessage {
+            header,
+            questions: vec![DnsQuestion {
+                domain_name: randomize_case(&question.domain_name),
+                ..question.clone()
+            }],
+            edns: Some(Edns {
+                udp_payload_size: SERVER_UDP_PAYLOAD_SIZE,
+                dnssec_ok,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        println!(
+            "Forwarding query for {} {}",
+            question.domain_name, question.question_type
+        );
+        let mut response = self.exchange(&query)?;
+        response.restore_case(&question.domain_name);
+        Ok(response)
+    }
+
+    /// Gets a reply to one query from the resolver, retrying with a longer timeout each time an
+    /// attempt fails, and switching to TCP for that attempt if the UDP reply is truncated. Each
+    /// query gets a socket of its own on a random port.
+    fn exchange(&self, query: &DnsMessage) -> Result<DnsMessage, anyhow::Error> {
+        let name = &query.questions[0].domain_name;
+        let query_bytes = query.to_bytes();
+        let resolver = self.resolver()?;
+        let socket = &bind_random_port(resolver)?;
+        let mut last_error = anyhow::anyhow!("no attempts made");
+        for (attempt, timeout) in self.config.attempt_timeouts().enumerate() {
+            let result = self
+                .exchange_udp(socket, resolver, query, &query_bytes, timeout)
+                .and_then(|response| {
+                    if response.header.truncation {
+                        println!("Upstream reply for {} truncated, retrying over TCP", name);
+                    } else if !echoes_case(query, &response) {
+                        // Either a spoofed reply that guessed the ID, or an upstream that doesn't
+                        // preserve case. TCP settles it without needing the case to match.
+                        println!(
+                            "Upstream reply for {} changed the 0x20 case, retrying over TCP",
+                            name
+                        );
+                    } else {
+                        return Ok(response);
+                    }
+                    self.exchange_tcp(resolver, query, &query_bytes, timeout)
+                });
+            match result {
+                Ok(response) => return Ok(response),
+                Err(e) => {
+                    eprintln!(
+                        "Upstream attempt {} for {} failed: {}",
+                        attempt + 1,
+                        name,
+                        e
+                    );
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error.context(format!(
+            "no usable reply from {} for {}",
+            self.resolver_addr, name
+        )))
+    }
+
+    fn resolver(&self) -> Result<SocketAddr, anyhow::Error> {
+        self.resolver_addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("resolver address {} not found", self.resolver_addr))
+    }
+
+    /// Sends the query and waits up to `timeout` for a matching reply. Anything else arriving on
+    /// the socket in the meantime (from another address, for another ID or question, or that
+    /// doesn't parse) is discarded, so a spoofed packet can't cut the wait short.
+    fn exchange_udp(
+        &self,
+        socket: &UdpSocket,
+        resolver: SocketAddr,
+        query: &DnsMessage,
+        query_bytes: &[u8],
+        timeout: Duration,
+    ) -> Result<DnsMessage, anyhow::Error> {
+        let mut receive_buf = [0; 1500];
+        let deadline = Instant::now() + timeout;
+        socket.send_to(query_bytes, resolver)?;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(anyhow::anyhow!("timed out after {:?}", timeout));
+            }
+            socket.set_read_timeout(Some(remaining))?;
+            let (len, source) = match socket.recv_from(&mut receive_buf) {
+                Ok(received) => received,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    return Err(anyhow::anyhow!("timed out after {:?}", timeout));
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if source != resolver {
+                eprintln!(
+                    "Discarding upstream reply from unexpected address {}",
+                    source
+                );
+                continue;
+            }
+            match DnsMessage::from_bytes(&receive_buf[..len]) {
+                Ok(response) if is_reply_to(query, &response) => return Ok(response),
+                Ok(response) => eprintln!(
+                    "Discarding upstream reply with ID {} that doesn't match the query",
+                    response.header.packet_identifier
+                ),
+                Err(e) => eprintln!("Discarding malformed upstream reply: {}", e),
+            }
+        }
+    }
+
+    /// Sends one query to the resolver over a fresh TCP connection and waits for its reply
+    fn exchange_tcp(
+        &self,
+        resolver: SocketAddr,
+        query: &DnsMessage,
+        query_bytes: &[u8],
+        timeout: Duration,
+    ) -> Result<DnsMessage, anyhow::Error> {
+        let mut stream = TcpStream::connect_timeout(&resolver, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        tcp::write_message(&mut stream, query_bytes)?;
+        let response = tcp::read_message(&mut stream)?.ok_or_else(|| {
+            anyhow::anyhow!("resolver closed the TCP connection without replying")
+        })?;
+        let response = DnsMessage::from_bytes(&response)?;
+        if !is_reply_to(query, &response) {
+            return Err(anyhow::anyhow!("TCP reply doesn't match the query"));
+        }
+        Ok(response)
+    }
+}
+
+/// Binds a socket for one upstream query on a random port, so an off-path attacker has to guess
+/// the port as well as the ID (RFC 5452 section 9.2)
+fn bind_random_port(resolver: SocketAddr) -> io::Result<UdpSocket> {
+    let ip = match resolver {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let mut rng = rand::thread_rng();
+    for _ in 0..SOURCE_PORT_BIND_ATTEMPTS {
+        match UdpSocket::bind((ip, rng.gen_range(MIN_SOURCE_PORT..=u16::MAX))) {
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+            result => return result,
+        }
+    }
+    UdpSocket::bind((ip, 0))
+}
+
+/// Flips the case of each letter at random (DNS 0x20, draft-vixie-dnsext-dns0x20), so a spoofed
+/// reply has to guess the casing of the name as well as the ID and port
+fn randomize_case(name: &DomainName) -> DomainName {
+    let mut rng = rand::thread_rng();
+    let labels = name
+        .iter_labels()
+        .map(|label| {
+            let bytes = label
+                .as_bytes()
+                .iter()
+                .map(|byte| {
+                    if rng.gen() {
+                        byte.to_ascii_uppercase()
+                    } else {
+                        byte.to_ascii_lowercase()
+                    }
+                })
+                .collect::<Vec<_>>();
+            Label::new(&bytes).expect("same length as an existing label")
+        })
+        .collect();
+    DomainName::from_labels(labels).expect("same length as an existing name")
+}
+
+/// Whether the reply's question has exactly the casing we sent
+fn echoes_case(query: &DnsMessage, response: &DnsMessage) -> bool {
+    response
+        .questions
+        .iter()
+        .zip(&query.questions)
+        .all(|(echoed, sent)| echoed.domain_name.eq_case_sensitive(&sent.domain_name))
+}
+
+/// Whether `response` answers `query`: it must be a response with the same ID that echoes the
+/// question (RFC 5452 section 9.1)
+fn is_reply_to(query: &DnsMessage, response: &DnsMessage) -> bool {
+    response.header.query_response_indicator
+        && response.header.packet_identifier == query.header.packet_identifier
+        && response.questions == query.questions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_protocol::{
+        dns_field_codes::{RecordClass, RecordType},
+        dns_resource_record::{RData, ResourceRecord},
+    };
+
+    #[test]
+    fn test_attempt_timeouts_back_off() {
+        let config = UpstreamConfig {
+            timeout: Duration::from_millis(100),
+            retries: 3,
+        };
+        assert_eq!(
+            config.attempt_timeouts().collect::<Vec<_>>(),
+            [100, 200, 400, 800].map(Duration::from_millis)
+        );
+        let config = UpstreamConfig {
+            retries: 0,
+            ..Default::default()
+        };
+        assert_eq!(config.attempt_timeouts().count(), 1);
+    }
+
+    #[test]
+    fn test_bind_random_port() {
+        let resolver = "127.0.0.1:53".parse().unwrap();
+        let sockets = (0..4)
+            .map(|_| bind_random_port(resolver).unwrap())
+            .collect::<Vec<_>>();
+        let mut ports = sockets
+            .iter()
+            .map(|socket| socket.local_addr().unwrap().port())
+            .collect::<Vec<_>>();
+        assert!(ports.iter().all(|&port| port >= MIN_SOURCE_PORT));
+        ports.sort_unstable();
+        ports.dedup();
+        assert_eq!(ports.len(), 4);
+    }
+
+    #[test]
+    fn test_randomize_and_restore_case() {
+        let name: DomainName = "www.example-123.com".parse().unwrap();
+        let randomized = (0..20).map(|_| randomize_case(&name)).collect::<Vec<_>>();
+        assert!(randomized.iter().all(|random| *random == name));
+        assert!(randomized
+            .iter()
+            .any(|random| !random.eq_case_sensitive(&name)));
+
+        let query = DnsMessage {
+            questions: vec![DnsQuestion {
+                domain_name: "wWw.ExAmPlE.cOm".parse().unwrap(),
+                question_type: RecordType::A,
+                class: RecordClass::In,
+            }],
+            ..Default::default()
+        };
+        let mut response = query.clone();
+        assert!(echoes_case(&query, &response));
+        response.questions[0].domain_name = "www.example.com".parse().unwrap();
+        assert!(!echoes_case(&query, &response));
+
+        response.answers = vec![ResourceRecord::new(
+            "WwW.eXaMpLe.CoM".parse().unwrap(),
+            RecordType::A,
+            RecordClass::In,
+            60,
+            RData::A(Ipv4Addr::LOCALHOST),
+        )];
+        let original: DomainName = "www.Example.com".parse().unwrap();
+        response.restore_case(&original);
+        assert!(response.answers[0].domain_name.eq_case_sensitive(&original));
+    }
+
+    #[test]
+    fn test_reserve_id() {
+        let forwarder = Forwarder::new("127.0.0.1:53".to_string(), UpstreamConfig::default());
+        let first = forwarder.reserve_id();
+        let second = forwarder.reserve_id();
+        assert_ne!(first.id, second.id);
+        assert_eq!(forwarder.outstanding.lock().unwrap().len(), 2);
+        drop(first);
+        assert_eq!(
+            *forwarder.outstanding.lock().unwrap(),
+            HashSet::from([second.id])
+        );
+    }
+
+    #[test]
+    fn test_is_reply_to() {
+        let query = DnsMessage {
+            header: DnsHeader {
+                packet_identifier: 0x1234,
+                ..Default::default()
+            },
+            questions: vec![DnsQuestion {
+                domain_name: "example.com".parse().unwrap(),
+                question_type: RecordType::A,
+                class: RecordClass::In,
+            }],
+            ..Default::default()
+        };
+        let mut response = query.clone();
+        response.header.query_response_indicator = true;
+        assert!(is_reply_to(&query, &response));
+
+        assert!(!is_reply_to(&query, &query));
+        let mut wrong_id = response.clone();
+        wrong_id.header.packet_identifier = 0x1235;
+        assert!(!is_reply_to(&query, &wrong_id));
+        let mut wrong_type = response.clone();
+        wrong_type.questions[0].question_type = RecordType::Aaaa;
+        assert!(!is_reply_to(&query, &wrong_type));
+        let mut wrong_name = response.clone();
+        wrong_name.questions[0].domain_name = "example.org".parse().unwrap();
+        assert!(!is_reply_to(&query, &wrong_name));
+        let mut no_question = response;
+        no_question.questions.clear();
+        assert!(!is_reply_to(&query, &no_question));
+    }
+
+    /// Binds UDP and TCP on the same free local port
+    fn bind_stub_resolver() -> (UdpSocket, std::net::TcpListener) {
+        loop {
+            let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+            if let Ok(tcp) = std::net::TcpListener::bind(udp.local_addr().unwrap()) {
+                return (udp, tcp);
+            }
+        }
+    }
+
+    #[test]
+    fn test_truncated_udp_reply_retried_over_tcp() {
+        let (udp, tcp) = bind_stub_resolver();
+        let resolver_addr = udp.local_addr().unwrap().to_string();
+        let answers = (1..=3)
+            .map(|last_octet| {
+                ResourceRecord::new(
+                    "example.com".parse().unwrap(),
+                    RecordType::A,
+                    RecordClass::In,
+                    60,
+                    RData::A(Ipv4Addr::new(192, 0, 2, last_octet)),
+                )
+            })
+            .collect::<Vec<_>>();
+        let full_answers = answers.clone();
+        let stub = std::thread::spawn(move || {
+            let mut buf = [0; 1500];
+            let (len, source) = udp.recv_from(&mut buf).unwrap();
+            let mut reply = DnsMessage::from_bytes(&buf[..len]).unwrap();
+            reply.header.query_response_indicator = true;
+            reply.header.truncation = true;
+            udp.send_to(&reply.to_bytes(), source).unwrap();
+
+            let (mut stream, _) = tcp.accept().unwrap();
+            let query = tcp::read_message(&mut stream).unwrap().unwrap();
+            let mut reply = DnsMessage::from_bytes(&query).unwrap();
+            reply.header.query_response_indicator = true;
+            reply.answers = full_answers;
+            tcp::write_message(&mut stream, &reply.to_bytes()).unwrap();
+        });
+
+        let forwarder = Forwarder::new(resolver_addr, UpstreamConfig::default());
+        let question = DnsQuestion {
+            domain_name: "example.com".parse().unwrap(),
+            question_type: RecordType::A,
+            class: RecordClass::In,
+        };
+        let response = forwarder
+            .forward_query(&question, true, false, false)
+            .unwrap();
+        stub.join().unwrap();
+        assert!(!response.header.truncation);
+        assert_eq!(response.answers, answers);
+    }
+}
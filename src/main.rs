@@ -1,18 +1,96 @@
 use clap::Parser;
 use codecrafters_dns_server::dns_server;
+use codecrafters_dns_server::dns_server::cache::CacheConfig;
+use codecrafters_dns_server::dns_server::server::{CacheFile, DEFAULT_WORKERS};
+use codecrafters_dns_server::dns_server::upstream::UpstreamConfig;
 use std::error::Error;
+use std::path::PathBuf;
+use std::time::Duration;
+/// Seconds between cache saves, unless configured otherwise
+const DEFAULT_CACHE_SAVE_INTERVAL: u64 = 300;
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
     #[arg(short, long)]
     resolver: String,
+    /// Milliseconds to wait for the first upstream attempt (doubled on each retry)
+    #[arg(long, default_value_t = UpstreamConfig::default().timeout.as_millis() as u64,
+          value_parser = clap::value_parser!(u64).range(1..))]
+    upstream_timeout_ms: u64,
+    /// Upstream attempts to make after the first one fails
+    #[arg(long, default_value_t = UpstreamConfig::default().retries)]
+    upstream_retries: u32,
+    /// Longest time in seconds an answer is cached for, whatever its TTL
+    #[arg(long, default_value_t = CacheConfig::default().max_ttl)]
+    cache_max_ttl: u32,
+    /// Longest time in seconds an NXDOMAIN or NODATA answer is cached for
+    #[arg(long, default_value_t = CacheConfig::default().max_negative_ttl)]
+    cache_max_negative_ttl: u32,
+    /// Seconds expired answers are kept to fall back on if the resolver can't be reached
+    #[arg(long, default_value_t = CacheConfig::default().stale_window)]
+    cache_stale_window: u32,
+    /// TTL in seconds given to expired answers served because the resolver couldn't be reached
+    #[arg(long, default_value_t = CacheConfig::default().stale_answer_ttl)]
+    cache_stale_ttl: u32,
+    /// Milliseconds a query waits for the resolver before getting an expired answer instead
+    #[arg(long, default_value_t = CacheConfig::default().client_response_timeout.as_millis() as u64)]
+    cache_stale_client_timeout_ms: u64,
+    /// Seconds after a failed lookup during which expired answers are served without asking again
+    #[arg(long, default_value_t = CacheConfig::default().failure_recheck)]
+    cache_failure_recheck: u32,
+    /// Refresh popular answers once this fraction of their TTL remains (0 to disable)
+    #[arg(long, default_value_t = CacheConfig::default().prefetch_threshold)]
+    cache_prefetch_threshold: f64,
+    /// Hits an answer needs before it's refreshed ahead of expiry
+    #[arg(long, default_value_t = CacheConfig::default().prefetch_min_hits)]
+    cache_prefetch_min_hits: u32,
+    /// Most bytes of records the cache may hold before evicting the least recently used
+    #[arg(long, default_value_t = CacheConfig::default().max_bytes)]
+    cache_max_bytes: usize,
+    /// File to keep the cache in across restarts
+    #[arg(long)]
+    cache_file: Option<PathBuf>,
+    /// Seconds between saves of the cache to --cache-file
+    #[arg(long, default_value_t = DEFAULT_CACHE_SAVE_INTERVAL,
+          value_parser = clap::value_parser!(u64).range(1..))]
+    cache_save_interval: u64,
+    /// Threads answering UDP queries
+    #[arg(long, default_value_t = DEFAULT_WORKERS,
+          value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    workers: usize,
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     // env::set_var("RUST_BACKTRACE", "full");
     let args = Args::parse();
     println!("Using resolver: {}", args.resolver);
-    let mut server = dns_server::server::Server::new("127.0.0.1".to_string(), 2053, args.resolver);
+    let upstream_config = UpstreamConfig {
+        timeout: Duration::from_millis(args.upstream_timeout_ms),
+        retries: args.upstream_retries,
+    };
+    let server = dns_server::server::Server::new(
+        "127.0.0.1".to_string(),
+        2053,
+        args.resolver,
+        upstream_config,
+        CacheConfig {
+            max_ttl: args.cache_max_ttl,
+            max_negative_ttl: args.cache_max_negative_ttl,
+            stale_window: args.cache_stale_window,
+            stale_answer_ttl: args.cache_stale_ttl,
+            client_response_timeout: Duration::from_millis(args.cache_stale_client_timeout_ms),
+            failure_recheck: args.cache_failure_recheck,
+            prefetch_threshold: args.cache_prefetch_threshold,
+            prefetch_min_hits: args.cache_prefetch_min_hits,
+            max_bytes: args.cache_max_bytes,
+        },
+        args.cache_file.map(|path| CacheFile {
+            path,
+            save_interval: Duration::from_secs(args.cache_save_interval),
+        }),
+        args.workers,
+    );
     server.start()?;
     Ok(())
 }